@@ -0,0 +1,452 @@
+//! Export of all account data (settings and timesheets) into a single zip or tar.gz archive
+//! and the import of such archives.
+//!
+//! Layout of an archive:
+//! - `manifest.json`: format and application version, creation time and a sha256 checksum of every other file
+//! - `settings.json`: the settings, if any have been stored
+//! - `timesheets/<YYYY_M>.timesheet`: one file per month
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::settings::Settings;
+use crate::timesheet::{FieldError, MonthKey, OneMonth};
+
+/// Version of the archive layout. Archives with a newer version are rejected.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Upper limit for the uncompressed size of an imported archive
+const MAX_UNCOMPRESSED_SIZE: u64 = 256 * 1024 * 1024;
+
+const MANIFEST: &str = "manifest.json";
+const SETTINGS: &str = "settings.json";
+const TIMESHEETS_DIR: &str = "timesheets/";
+const TIMESHEET_SUFFIX: &str = ".timesheet";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub const fn content_type(self) -> &'static str {
+        match self {
+            Self::Zip => "application/zip",
+            Self::TarGz => "application/gzip",
+        }
+    }
+
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Zip => "zip",
+            Self::TarGz => "tar.gz",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded sha256 of the file content
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub format_version: u32,
+    /// Version of the timesheet backend that created the archive
+    pub app_version: String,
+    /// Unix timestamp in seconds
+    pub created: u64,
+    /// Credentials have been removed from the settings
+    pub redacted: bool,
+    pub files: Vec<ManifestFile>,
+}
+
+/// All data of an account that is part of an archive
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub settings: Option<Settings>,
+    pub months: BTreeMap<MonthKey, OneMonth>,
+}
+
+fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn file_size(data: &[u8]) -> Result<u64, io::Error> {
+    u64::try_from(data.len()).map_err(io::Error::other)
+}
+
+/// Creates an archive of `account`. With `redact`, credentials are removed from the settings.
+///
+/// # Errors
+/// Fails if the settings or a timesheet can not be serialized or the archive can not be written.
+pub fn write(account: &Account, format: ArchiveFormat, redact: bool, created: u64) -> Result<Vec<u8>, io::Error> {
+    let mut files = Vec::new();
+    if let Some(settings) = &account.settings {
+        let mut settings = settings.clone();
+        if redact {
+            settings.redact_secrets();
+        }
+        files.push((SETTINGS.to_string(), serde_json::to_vec_pretty(&settings)?));
+    }
+    for (date, month) in &account.months {
+        files.push((format!("{TIMESHEETS_DIR}{date}{TIMESHEET_SUFFIX}"), serde_json::to_vec_pretty(month)?));
+    }
+
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        created,
+        redacted: redact,
+        files: files.iter()
+            .map(|(path, data)| Ok(ManifestFile { path: path.clone(), size: file_size(data)?, sha256: sha256(data) }))
+            .collect::<Result<_, io::Error>>()?,
+    };
+    files.insert(0, (MANIFEST.to_string(), serde_json::to_vec_pretty(&manifest)?));
+
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+            for (path, data) in &files {
+                zip.start_file(path.as_str(), options)?;
+                zip.write_all(data)?;
+            }
+            Ok(zip.finish()?.into_inner())
+        }
+        ArchiveFormat::TarGz => {
+            let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+            for (path, data) in &files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(file_size(data)?);
+                header.set_mode(0o644);
+                header.set_mtime(created);
+                header.set_cksum();
+                tar.append_data(&mut header, path, data.as_slice())?;
+            }
+            tar.into_inner()?.finish()
+        }
+    }
+}
+
+/// Collects all regular files of a zip or tar.gz archive, detected by its magic bytes
+fn unpack(data: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    let mut files = HashMap::new();
+    let mut remaining = MAX_UNCOMPRESSED_SIZE;
+    let mut add = |path: String, reader: &mut dyn Read| -> Result<(), String> {
+        let mut content = Vec::new();
+        reader.take(remaining + 1).read_to_end(&mut content).map_err(|err| format!("Failed to read {path}: {err}"))?;
+        remaining = u64::try_from(content.len()).ok()
+            .and_then(|size| remaining.checked_sub(size))
+            .ok_or("Archive is too large")?;
+        if files.insert(path.clone(), content).is_some() {
+            return Err(format!("Duplicate file {path}"));
+        }
+        Ok(())
+    };
+
+    if data.starts_with(b"PK\x03\x04") {
+        let mut zip = zip::ZipArchive::new(Cursor::new(data)).map_err(|err| format!("Invalid zip archive: {err}"))?;
+        for index in 0..zip.len() {
+            let mut file = zip.by_index(index).map_err(|err| format!("Invalid zip archive: {err}"))?;
+            if file.is_file() {
+                add(file.name().to_string(), &mut file)?;
+            }
+        }
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(data));
+        let entries = tar.entries().map_err(|err| format!("Invalid tar.gz archive: {err}"))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|err| format!("Invalid tar.gz archive: {err}"))?;
+            if entry.header().entry_type().is_file() {
+                let path = entry.path().map_err(|err| format!("Invalid tar.gz archive: {err}"))?.to_string_lossy().into_owned();
+                add(path, &mut entry)?;
+            }
+        }
+    } else {
+        return Err("Unknown archive format. Expected zip or tar.gz".into());
+    }
+    Ok(files)
+}
+
+/// Reads an archive created by [`write`].
+///
+/// # Errors
+/// Fails if the archive or one of its files is invalid, a checksum does not match
+/// or the archive contains files that are not listed in the manifest.
+pub fn read(archive: &[u8]) -> Result<(Manifest, Account), String> {
+    let mut files = unpack(archive)?;
+
+    let manifest: Manifest = serde_json::from_slice(&files.remove(MANIFEST).ok_or("Missing manifest.json")?)
+        .map_err(|err| format!("Invalid manifest.json: {err}"))?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(format!("Unsupported archive format version {}. Update the timesheet backend to version {} or newer", manifest.format_version, manifest.app_version));
+    }
+
+    for file in &manifest.files {
+        let content = files.get(&file.path).ok_or_else(|| format!("Missing file {}", file.path))?;
+        if usize::try_from(file.size).ok() != Some(content.len()) || sha256(content) != file.sha256 {
+            return Err(format!("Checksum mismatch for {}", file.path));
+        }
+    }
+    if let Some(path) = files.keys().find(|path| !manifest.files.iter().any(|file| &file.path == *path)) {
+        return Err(format!("File {path} is not listed in the manifest"));
+    }
+
+    let mut account = Account::default();
+    for (path, content) in files {
+        if path == SETTINGS {
+            account.settings = Some(serde_json::from_slice(&content).map_err(|err| format!("Invalid {path}: {err}"))?);
+            continue;
+        }
+        let date: MonthKey = path.strip_prefix(TIMESHEETS_DIR)
+            .and_then(|name| name.strip_suffix(TIMESHEET_SUFFIX))
+            .ok_or_else(|| format!("Unexpected file {path}"))?
+            .parse()
+            .map_err(|err| format!("{path}: {err}"))?;
+        let month: OneMonth = serde_json::from_slice(&content).map_err(|err| format!("Invalid {path}: {err}"))?;
+        if (month.year, month.month) != (date.year, date.month) {
+            return Err(format!("{path} contains {}-{:02}", month.year, month.month));
+        }
+        account.months.insert(date, month);
+    }
+    Ok((manifest, account))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// The server data is replaced by the archive. Timesheets that are not part of the archive are deleted.
+    #[default]
+    Replace,
+    /// Timesheets of the archive are only taken if they are missing or newer than the server copy.
+    /// Projects, tags and filters of the settings are combined.
+    Merge,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+    Unchanged,
+    /// The server copy is kept because it is newer than the archive (merge mode)
+    Keep,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MonthChange {
+    pub date: MonthKey,
+    pub action: Action,
+    pub server_change_id: Option<u64>,
+    pub archive_change_id: Option<u64>,
+}
+
+/// What an import changes (or would change, for a dry-run)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImportReport {
+    pub mode: ImportMode,
+    pub dry_run: bool,
+    pub manifest: Manifest,
+    pub settings: Action,
+    pub months: Vec<MonthChange>,
+}
+
+/// The changes to apply for an import
+pub struct ImportPlan {
+    /// Settings to store, `None` if they are unchanged
+    pub settings: Option<Settings>,
+    pub put: Vec<(MonthKey, OneMonth)>,
+    pub delete: Vec<MonthKey>,
+    pub report: ImportReport,
+}
+
+impl ImportPlan {
+    /// Checks the timesheets to write against the settings of the account after the import. The fields
+    /// are prefixed with the date of their timesheet, ie `2022_4.days[3].entries[0].project`.
+    pub fn validate(&self, current: &Account) -> Vec<FieldError> {
+        let settings = self.settings.as_ref().or(current.settings.as_ref());
+        self.put.iter()
+            .flat_map(|(date, month)| {
+                month.validate(settings.map(|s| s.projects.as_slice()), settings.map(|s| s.tags.as_slice()))
+                    .into_iter()
+                    .map(move |error| FieldError::new(format!("{date}.{}", error.field), error.message))
+            })
+            .collect()
+    }
+}
+
+/// Compares the archive against the server data. Timesheets that are written get a `change_id`
+/// above the server copy, so that clients holding the old version notice the change.
+pub fn plan(current: &Account, manifest: Manifest, mut archive: Account, mode: ImportMode, dry_run: bool) -> ImportPlan {
+    let (settings_action, settings) = match (&current.settings, archive.settings.take()) {
+        (_, None) => (Action::Unchanged, None),
+        (None, Some(settings)) => (Action::Create, Some(settings)),
+        (Some(current), Some(mut settings)) => {
+            match mode {
+                ImportMode::Replace => settings.restore_secrets(current),
+                ImportMode::Merge => {
+                    let mut merged = current.clone();
+                    merged.merge(&settings);
+                    settings = merged;
+                }
+            }
+            settings.last_updated = current.last_updated;
+            if &settings == current {
+                (Action::Unchanged, None)
+            } else {
+                (Action::Update, Some(settings))
+            }
+        }
+    };
+
+    let mut months = Vec::new();
+    let mut put = Vec::new();
+    let mut delete = Vec::new();
+    let dates: std::collections::BTreeSet<MonthKey> = current.months.keys().chain(archive.months.keys()).copied().collect();
+    for date in dates {
+        let (server, imported) = (current.months.get(&date), archive.months.remove(&date));
+        let server_change_id = server.map(|month| month.change_id);
+        let archive_change_id = imported.as_ref().map(|month| month.change_id);
+        let action = match (server, imported) {
+            (None, Some(imported)) => {
+                put.push((date, imported));
+                Action::Create
+            }
+            (Some(_), None) if mode == ImportMode::Replace => {
+                delete.push(date);
+                Action::Delete
+            }
+            (Some(_), None) => Action::Keep,
+            (Some(server), Some(imported)) if server.days == imported.days => Action::Unchanged,
+            (Some(server), Some(imported)) if mode == ImportMode::Merge && imported.change_id <= server.change_id => Action::Keep,
+            (Some(server), Some(mut month)) => {
+                month.change_id = server.change_id.max(month.change_id) + 1;
+                put.push((date, month));
+                Action::Update
+            }
+            (None, None) => continue,
+        };
+        months.push(MonthChange { date, action, server_change_id, archive_change_id });
+    }
+
+    ImportPlan {
+        settings,
+        put,
+        delete,
+        report: ImportReport { mode, dry_run, manifest, settings: settings_action, months },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timesheet::{days_in_month, DayEntry, OneDay};
+
+    fn month(month: u8, change_id: u64, minutes: i64) -> OneMonth {
+        let mut days = vec![OneDay::default(); days_in_month(2022, month).unwrap() as usize];
+        days[0].entries.push(DayEntry { duration: minutes, ..DayEntry::default() });
+        OneMonth { days, year: 2022, month, created: 1, change_id }
+    }
+
+    fn account() -> Account {
+        let settings = Settings { name: "Jane".into(), ics_url: "https://outlook/secret.ics".into(), projects: vec!["A".into()], ..Settings::default() };
+        let months = [(1, month(1, 3, 60)), (2, month(2, 1, 30))]
+            .into_iter()
+            .map(|(key, month)| (MonthKey { year: 2022, month: key }, month))
+            .collect();
+        Account { settings: Some(settings), months }
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [ArchiveFormat::Zip, ArchiveFormat::TarGz] {
+            let data = write(&account(), format, false, 1_700_000_000).unwrap();
+            let (manifest, read_back) = read(&data).unwrap();
+            assert_eq!(read_back, account());
+            assert_eq!(manifest.files.len(), 3);
+            assert!(!manifest.redacted);
+        }
+
+        let data = write(&account(), ArchiveFormat::Zip, true, 0).unwrap();
+        let (manifest, read_back) = read(&data).unwrap();
+        assert!(manifest.redacted);
+        assert_eq!(read_back.settings.unwrap().ics_url, "");
+    }
+
+    #[test]
+    fn rejects_tampered_archive() {
+        let data = write(&account(), ArchiveFormat::TarGz, false, 0).unwrap();
+        let mut files = unpack(&data).unwrap();
+        files.insert("timesheets/2022_1.timesheet".into(), serde_json::to_vec(&month(1, 3, 600)).unwrap());
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, content) in &files {
+            zip.start_file(path.as_str(), zip::write::FileOptions::default()).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        let tampered = zip.finish().unwrap().into_inner();
+        assert_eq!(read(&tampered).unwrap_err(), "Checksum mismatch for timesheets/2022_1.timesheet");
+        assert!(read(b"not an archive").is_err());
+    }
+
+    #[test]
+    fn plan_replace_and_merge() {
+        let current = account();
+        let mut archive = account();
+        let january = MonthKey { year: 2022, month: 1 };
+        let february = MonthKey { year: 2022, month: 2 };
+        let march = MonthKey { year: 2022, month: 3 };
+        archive.months.remove(&february);
+        archive.months.insert(january, month(1, 2, 120));
+        archive.months.insert(march, month(3, 1, 15));
+        let mut settings = archive.settings.clone().unwrap();
+        settings.redact_secrets();
+        settings.projects.push("B".into());
+        archive.settings = Some(settings);
+
+        let manifest = read(&write(&archive, ArchiveFormat::Zip, true, 0).unwrap()).unwrap().0;
+        let actions = |plan: &ImportPlan| plan.report.months.iter().map(|change| change.action).collect::<Vec<_>>();
+
+        let replace = plan(&current, manifest.clone(), archive.clone(), ImportMode::Replace, false);
+        assert_eq!(actions(&replace), [Action::Update, Action::Delete, Action::Create]);
+        assert_eq!(replace.put[0].1.change_id, 4);
+        assert_eq!(replace.delete, [february]);
+        let settings = replace.settings.unwrap();
+        assert_eq!(settings.ics_url, "https://outlook/secret.ics");
+        assert_eq!(settings.projects, ["A", "B"]);
+
+        // The archive copy of January is older than the server copy
+        let merge = plan(&current, manifest, archive, ImportMode::Merge, true);
+        assert_eq!(actions(&merge), [Action::Keep, Action::Keep, Action::Create]);
+        assert_eq!(merge.put.len(), 1);
+        assert!(merge.delete.is_empty());
+    }
+
+    #[test]
+    fn plan_validates_timesheets() {
+        let current = account();
+        let mut archive = account();
+        let mut march = month(3, 1, 15);
+        march.days[0].entries[0].project = vec!["B".into()];
+        archive.months.insert(MonthKey { year: 2022, month: 3 }, march);
+        let manifest = read(&write(&archive, ArchiveFormat::Zip, false, 0).unwrap()).unwrap().0;
+
+        let errors = plan(&current, manifest.clone(), archive.clone(), ImportMode::Replace, true).validate(&current);
+        assert_eq!(errors, [FieldError::new("2022_3.days[0].entries[0].project", "Unknown project \"B\"")]);
+
+        // The projects of the imported settings count
+        archive.settings.as_mut().unwrap().projects.push("B".into());
+        assert!(plan(&current, manifest, archive, ImportMode::Merge, true).validate(&current).is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+//! CalDAV sources (RFC 4791): discovery of the calendars of an account and time-range queries for their events
+
+use axum::http::{header, StatusCode};
+use chrono::DateTime;
+use reqwest::{Client, Method, Url};
+use roxmltree::{Document, Node};
+
+use crate::settings::CalendarSource;
+
+use super::fetch::{authorize, client};
+use super::{location, Location, TimeRange};
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+/// One `response` of a multi-status, with the properties that are used here
+struct Resource {
+    url: Url,
+    /// The resource is a calendar collection
+    calendar: bool,
+    name: String,
+    /// Components the calendar can contain, any if empty
+    components: Vec<String>,
+    principal: Option<Url>,
+    home: Option<Url>,
+    data: Option<String>,
+}
+
+impl Resource {
+    /// Matches the display name or the last path segment
+    fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.url.path_segments().and_then(|mut segments| segments.rfind(|segment| !segment.is_empty())) == Some(name)
+    }
+}
+
+struct Dav<'a> {
+    source: &'a CalendarSource,
+    client: Client,
+    http_status: &'a mut Option<u16>,
+}
+
+/// The `calendar-data` of the events that overlap `range`, in the calendars of `source`, as one ICS document.
+/// Records the HTTP status of the last request in `http_status`.
+pub(super) async fn calendar_data(source: &CalendarSource, range: TimeRange, http_status: &mut Option<u16>) -> Result<String, (StatusCode, String)> {
+    let url = match location(source).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))? {
+        Location::Http(url) => url,
+        Location::File(_) => return Err((StatusCode::UNPROCESSABLE_ENTITY, format!("Calendar {}: CalDAV needs an http url", source.name))),
+    };
+    let client = client(source).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let mut dav = Dav { source, client, http_status };
+
+    let calendars: Vec<_> = dav.discover(url).await?
+        .into_iter()
+        .filter(|calendar| source.calendar.is_empty() || calendar.is_named(&source.calendar))
+        .collect();
+    if calendars.is_empty() {
+        return Err((StatusCode::NOT_FOUND, format!("Calendar {}: No CalDAV calendar found", source.name)));
+    }
+
+    let mut data = String::new();
+    for calendar in calendars {
+        for ics in dav.query(&calendar.url, range).await? {
+            data.push_str(ics.trim_end());
+            data.push_str("\r\n");
+        }
+    }
+    Ok(data)
+}
+
+impl Dav<'_> {
+    /// The calendar at `url`, or the event calendars in the calendar home of the account at `url`
+    async fn discover(&mut self, url: Url) -> Result<Vec<Resource>, (StatusCode, String)> {
+        let resource = self.propfind(&url, "0", "<d:resourcetype/><d:current-user-principal/><c:calendar-home-set/>").await?
+            .into_iter().next()
+            .ok_or_else(|| (StatusCode::BAD_GATEWAY, format!("Empty response of {url}")))?;
+        if resource.calendar {
+            return Ok(vec![resource]);
+        }
+
+        let home = match (resource.home, resource.principal) {
+            (Some(home), _) => Some(home),
+            (None, Some(principal)) => self.propfind(&principal, "0", "<c:calendar-home-set/>").await?
+                .into_iter().find_map(|resource| resource.home),
+            (None, None) => None,
+        };
+        let home = home.ok_or_else(|| (StatusCode::NOT_FOUND, format!("Calendar {}: No CalDAV calendar home at {}", self.source.name, url)))?;
+
+        let calendars = self.propfind(&home, "1", "<d:resourcetype/><d:displayname/><c:supported-calendar-component-set/>").await?
+            .into_iter()
+            .filter(|resource| resource.calendar && (resource.components.is_empty() || resource.components.iter().any(|component| component == "VEVENT")))
+            .collect();
+        Ok(calendars)
+    }
+
+    async fn propfind(&mut self, url: &Url, depth: &str, props: &str) -> Result<Vec<Resource>, (StatusCode, String)> {
+        let body = format!(r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav"><d:prop>{props}</d:prop></d:propfind>"#);
+        self.request("PROPFIND", url, depth, body).await
+    }
+
+    /// The `calendar-data` of the events in the calendar at `url` that overlap `range`.
+    /// Recurring events are returned unexpanded, if one of their occurrences overlaps.
+    async fn query(&mut self, url: &Url, range: TimeRange) -> Result<Vec<String>, (StatusCode, String)> {
+        let utc = |time| DateTime::from_timestamp(time, 0).unwrap_or_default().format("%Y%m%dT%H%M%SZ");
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav"><d:prop><c:calendar-data/></d:prop><c:filter><c:comp-filter name="VCALENDAR"><c:comp-filter name="VEVENT"><c:time-range start="{}" end="{}"/></c:comp-filter></c:comp-filter></c:filter></c:calendar-query>"#,
+            utc(range.start), utc(range.end),
+        );
+        let resources = self.request("REPORT", url, "1", body).await?;
+        Ok(resources.into_iter().filter_map(|resource| resource.data).collect())
+    }
+
+    /// Sends a WebDAV request and parses its multi-status response
+    async fn request(&mut self, method: &str, url: &Url, depth: &str, body: String) -> Result<Vec<Resource>, (StatusCode, String)> {
+        let failed = |err: reqwest::Error| {
+            tracing::error!("Failed {} of CalDAV {} at {}: {:?}", method, self.source.name, url, err);
+            (StatusCode::NOT_FOUND, err.to_string())
+        };
+        let method = Method::from_bytes(method.as_bytes()).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let request = self.client.request(method.clone(), url.clone())
+            .header("Depth", depth)
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(body);
+        let res = authorize(request, self.source).send().await.map_err(failed)?;
+        let status = res.status();
+        *self.http_status = Some(status.as_u16());
+        let text = res.text().await.map_err(failed)?;
+        if status != StatusCode::MULTI_STATUS {
+            tracing::info!("{} of CalDAV {} at {} failed {} {}", method, self.source.name, url, status, &text);
+            return Err((status, text));
+        }
+        multi_status(&text, url).map_err(|err| (StatusCode::BAD_GATEWAY, format!("Invalid response of {url}: {err}")))
+    }
+}
+
+/// Parses the responses of a multi-status. Hrefs are relative to `base`.
+fn multi_status(xml: &str, base: &Url) -> Result<Vec<Resource>, roxmltree::Error> {
+    let document = Document::parse(xml)?;
+    let href = |node: Node| node.children()
+        .find(|child| child.has_tag_name((DAV, "href")))
+        .and_then(|href| href.text())
+        .and_then(|href| base.join(href.trim()).ok());
+
+    let resources = document.descendants()
+        .filter(|node| node.has_tag_name((DAV, "response")))
+        .filter_map(|response| {
+            let property = |namespace, name| response.descendants().find(|node| node.has_tag_name((namespace, name)));
+            Some(Resource {
+                url: href(response)?,
+                calendar: property(DAV, "resourcetype").is_some_and(|types| types.children().any(|child| child.has_tag_name((CALDAV, "calendar")))),
+                name: property(DAV, "displayname").and_then(|name| name.text()).unwrap_or_default().to_string(),
+                components: property(CALDAV, "supported-calendar-component-set")
+                    .map(|set| set.children().filter_map(|component| component.attribute("name")).map(str::to_string).collect())
+                    .unwrap_or_default(),
+                principal: property(DAV, "current-user-principal").and_then(href),
+                home: property(CALDAV, "calendar-home-set").and_then(href),
+                data: property(CALDAV, "calendar-data").and_then(|data| data.text()).map(str::to_string),
+            })
+        })
+        .collect();
+    Ok(resources)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::{HeaderMap, Uri};
+    use axum::response::{IntoResponse, Response};
+    use axum::Router;
+
+    use super::*;
+    use crate::ics::{convert, parse};
+    use crate::settings::SourceKind;
+
+    fn multi_status(responses: &str) -> Response {
+        let body = format!(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:" xmlns:cal="urn:ietf:params:xml:ns:caldav">{responses}</d:multistatus>"#);
+        (StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response()
+    }
+
+    fn calendar(name: &str, components: &[&str]) -> String {
+        let components = components.iter().map(|component| format!(r#"<cal:comp name="{component}"/>"#)).collect::<Vec<_>>().concat();
+        format!(
+            r"<d:response><d:href>/dav/calendars/me/{}/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/><cal:calendar/></d:resourcetype><d:displayname>{}</d:displayname><cal:supported-calendar-component-set>{}</cal:supported-calendar-component-set></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+            name.to_lowercase(), name, components,
+        )
+    }
+
+    fn event(uid: &str, start: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:{uid}\r\nSUMMARY:{uid}\r\nDTSTART:{start}\r\nDURATION:PT1H\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n")
+    }
+
+    /// A CalDAV server with the calendars Work, Tasks and Private, in the style of Nextcloud and Radicale
+    fn stand_in(requests: Arc<Mutex<Vec<(String, String, String)>>>) -> Url {
+        let app = Router::new().fallback(move |method: Method, uri: Uri, headers: HeaderMap, body: String| {
+            let requests = requests.clone();
+            async move {
+                if headers.get(header::AUTHORIZATION).is_none() {
+                    return StatusCode::UNAUTHORIZED.into_response();
+                }
+                requests.lock().unwrap().push((method.to_string(), uri.path().to_string(), body));
+                match (method.as_str(), uri.path()) {
+                    ("PROPFIND", "/dav/") => multi_status(r"<d:response><d:href>/dav/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:current-user-principal><d:href>/dav/principals/me/</d:href></d:current-user-principal></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat><d:propstat><d:prop><cal:calendar-home-set/></d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat></d:response>"),
+                    ("PROPFIND", "/dav/principals/me/") => multi_status(r"<d:response><d:href>/dav/principals/me/</d:href><d:propstat><d:prop><cal:calendar-home-set><d:href>/dav/calendars/me/</d:href></cal:calendar-home-set></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"),
+                    ("PROPFIND", "/dav/calendars/me/") => multi_status(&format!(
+                        r"<d:response><d:href>/dav/calendars/me/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>{}{}{}",
+                        calendar("Work", &["VEVENT", "VTODO"]), calendar("Tasks", &["VTODO"]), calendar("Private", &[]),
+                    )),
+                    ("PROPFIND", "/dav/calendars/me/private/") => multi_status(&calendar("Private", &[])),
+                    ("REPORT", "/dav/calendars/me/work/") => multi_status(&format!(
+                        r"<d:response><d:href>/dav/calendars/me/work/standup.ics</d:href><d:propstat><d:prop><cal:calendar-data><![CDATA[{}]]></cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                        event("standup", "20231114T090000Z"),
+                    )),
+                    ("REPORT", "/dav/calendars/me/private/") => multi_status(&format!(
+                        r"<d:response><d:href>/dav/calendars/me/private/doctor.ics</d:href><d:propstat><d:prop><cal:calendar-data>{}</cal:calendar-data></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>",
+                        event("doctor", "20231114T150000Z").replace('\r', "&#13;"),
+                    )),
+                    _ => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+        });
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = Url::parse(&format!("http://{}/dav/", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn discovers_and_queries_calendars() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let url = stand_in(requests.clone());
+        let source = CalendarSource {
+            name: "Nextcloud".into(),
+            url: url.to_string(),
+            kind: SourceKind::Caldav,
+            username: "me".into(),
+            password: "secret".into(),
+            allow_http: true,
+            ..Default::default()
+        };
+        // 2023-11-14 UTC
+        let range = TimeRange { start: 1_699_920_000, end: 1_700_006_400 };
+        let uids = |data: &str| convert(&parse(data), range).0.into_iter().map(|entry| entry.uid).collect::<Vec<_>>();
+
+        let mut http_status = None;
+        let data = calendar_data(&source, range, &mut http_status).await.unwrap();
+        assert_eq!(uids(&data), ["standup", "doctor"]);
+        assert_eq!(http_status, Some(207));
+        // From the principal to the calendar home, the task list is not queried
+        let paths: Vec<_> = requests.lock().unwrap().iter().map(|(method, path, _)| format!("{method} {path}")).collect();
+        assert_eq!(paths, [
+            "PROPFIND /dav/", "PROPFIND /dav/principals/me/", "PROPFIND /dav/calendars/me/",
+            "REPORT /dav/calendars/me/work/", "REPORT /dav/calendars/me/private/",
+        ]);
+        let report = requests.lock().unwrap()[3].2.clone();
+        assert!(report.contains(r#"<c:time-range start="20231114T000000Z" end="20231115T000000Z"/>"#), "{report}");
+
+        // One calendar of the account, by name
+        let private = CalendarSource { calendar: "Private".into(), ..source.clone() };
+        assert_eq!(uids(&calendar_data(&private, range, &mut http_status).await.unwrap()), ["doctor"]);
+        // The url of a calendar is queried directly
+        requests.lock().unwrap().clear();
+        let direct = CalendarSource { url: url.join("calendars/me/private/").unwrap().to_string(), ..source.clone() };
+        assert_eq!(uids(&calendar_data(&direct, range, &mut http_status).await.unwrap()), ["doctor"]);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let unauthorized = CalendarSource { username: String::new(), ..source.clone() };
+        assert_eq!(calendar_data(&unauthorized, range, &mut http_status).await.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let missing = CalendarSource { calendar: "Holidays".into(), ..source };
+        assert_eq!(calendar_data(&missing, range, &mut http_status).await.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,241 @@
+//! Rendering of the timesheets as ICS calendar, to subscribe to the booked work in a calendar app
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::settings::{FeedTimes, TimesheetFeed};
+use crate::timesheet::{DayEntry, OneMonth};
+
+/// Compiled feed settings
+pub struct Feed {
+    day_start: NaiveTime,
+    zone: chrono_tz::Tz,
+    times: FeedTimes,
+}
+
+impl Feed {
+    pub fn new(feed: &TimesheetFeed) -> Result<Self, String> {
+        let day_start = NaiveTime::parse_from_str(&feed.day_start, "%H:%M")
+            .map_err(|err| format!("Invalid day start {}: {err}", feed.day_start))?;
+        let zone = feed.time_zone.as_deref()
+            .map(|name| chrono_tz::Tz::from_str(name).map_err(|err| format!("Invalid time zone {name}: {err}")))
+            .transpose()?
+            .unwrap_or(chrono_tz::UTC);
+        Ok(Self { day_start, zone, times: feed.times })
+    }
+
+    /// Overrides how the times of the events are chosen
+    pub const fn with_times(self, times: FeedTimes) -> Self {
+        Self { times, ..self }
+    }
+
+    /// One event per entry of the `months`, and all-day events for sick days and holidays
+    pub fn render(&self, months: &[OneMonth], now: i64) -> String {
+        let mut lines: Vec<String> = ["BEGIN:VCALENDAR", "VERSION:2.0", "PRODID:-//Timesheets//Timesheets//EN", "CALSCALE:GREGORIAN", "X-WR-CALNAME:Timesheets"]
+            .into_iter().map(str::to_string).collect();
+        let stamp = utc(now);
+
+        for month in months {
+            for (index, day) in month.days.iter().enumerate() {
+                let Some(date) = u32::try_from(index + 1).ok().and_then(|day| NaiveDate::from_ymd_opt(month.year, u32::from(month.month), day)) else {
+                    continue;
+                };
+                let uid = |id: &str| format!("UID:{}-{id}@timesheets", date.format("%Y%m%d"));
+
+                for (id, summary, set) in [("sick", "Sick", day.sick), ("holiday", "Holiday", day.holiday)] {
+                    if set {
+                        lines.extend([
+                            "BEGIN:VEVENT".to_string(),
+                            uid(id),
+                            format!("DTSTAMP:{stamp}"),
+                            format!("DTSTART;VALUE=DATE:{}", date.format("%Y%m%d")),
+                            format!("DTEND;VALUE=DATE:{}", (date + Duration::days(1)).format("%Y%m%d")),
+                            format!("SUMMARY:{summary}"),
+                            "TRANSP:TRANSPARENT".to_string(),
+                            "END:VEVENT".to_string(),
+                        ]);
+                    }
+                }
+
+                // A day start that is skipped by a DST change is taken as UTC
+                let local = date.and_time(self.day_start);
+                let mut next = self.zone.from_local_datetime(&local).earliest()
+                    .map_or_else(|| Utc.from_utc_datetime(&local).timestamp(), |start| start.timestamp());
+                let mut ids = HashMap::new();
+                for entry in &day.entries {
+                    let start = if let (FeedTimes::Imported, Some(start)) = (self.times, entry.start) {
+                        start
+                    } else {
+                        next += entry.duration * 60;
+                        next - entry.duration * 60
+                    };
+                    // Identical entries of a day are numbered
+                    let id = entry_id(entry);
+                    let count = ids.entry(id.clone()).or_insert(0);
+                    *count += 1;
+                    let id = if *count == 1 { id } else { format!("{id}-{count}") };
+                    lines.extend([
+                        "BEGIN:VEVENT".to_string(),
+                        uid(&id),
+                        format!("DTSTAMP:{stamp}"),
+                        format!("DTSTART:{}", utc(start)),
+                        format!("DTEND:{}", utc(start + entry.duration * 60)),
+                        format!("SUMMARY:{}", escape(&summary(entry))),
+                    ]);
+                    if !entry.description.is_empty() {
+                        lines.push(format!("DESCRIPTION:{}", escape(&entry.description)));
+                    }
+                    if !entry.tags.is_empty() {
+                        lines.push(format!("CATEGORIES:{}", entry.tags.iter().map(|tag| escape(tag)).collect::<Vec<_>>().join(",")));
+                    }
+                    lines.push("END:VEVENT".to_string());
+                }
+            }
+        }
+
+        lines.push("END:VCALENDAR".to_string());
+        lines.iter().map(|line| fold(line)).collect()
+    }
+}
+
+/// Identifies an entry within its day, so that calendar apps keep track of it when other entries change:
+/// the UID of the imported calendar event, or a hash of project, tags, description and imported start
+fn entry_id(entry: &DayEntry) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(uid) = entry.import_tags.first() {
+        hasher.update(uid.as_bytes());
+    } else {
+        for part in [entry.project.join(","), entry.tags.join(","), entry.description.clone(), entry.start.map(|start| start.to_string()).unwrap_or_default()] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+    }
+    hex::encode(&hasher.finalize()[..8])
+}
+
+/// Projects and the first line of the description, ie `Falco: Code review`
+fn summary(entry: &DayEntry) -> String {
+    let project = entry.project.join(", ");
+    let description = entry.description.lines().next().unwrap_or_default();
+    match (project.is_empty(), description.is_empty()) {
+        (false, false) => format!("{project}: {description}"),
+        (false, true) => project,
+        (true, false) => description.to_string(),
+        (true, true) => "Timesheet entry".to_string(),
+    }
+}
+
+fn utc(time: i64) -> String {
+    DateTime::from_timestamp(time, 0).unwrap_or_default().format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a `TEXT` value
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Folds a content line after 75 octets, without splitting characters, and ends it with CRLF
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ics::{convert, parse, TimeRange};
+    use crate::timesheet::OneDay;
+
+    fn entry(project: &str, description: &str, duration: i64, start: Option<i64>) -> DayEntry {
+        DayEntry { project: vec![project.into()], description: description.into(), duration, tags: vec!["meeting".into()], start, ..DayEntry::default() }
+    }
+
+    #[test]
+    fn renders_entries_and_absences() {
+        let mut days = vec![OneDay::default(); 30];
+        // 2023-11-14 10:00 UTC
+        let imported = 1_699_956_000;
+        days[13].entries = vec![
+            entry("Falco", "Code review, part 1; see the ticket\nand the wiki", 90, None),
+            entry("Agami", "Planning", 30, Some(imported)),
+            entry("", "A very long description that needs to be folded, because content lines are limited to 75 octets", 15, None),
+        ];
+        days[14].sick = true;
+        let months = [OneMonth { days, year: 2023, month: 11, created: 1, change_id: 1 }];
+        let settings = TimesheetFeed { time_zone: Some("Europe/Berlin".into()), ..TimesheetFeed::default() };
+        let range = TimeRange { start: 1_699_920_000, end: 1_700_092_800 };
+        let events = |ics: &str| convert(&parse(ics), range).0
+            .into_iter()
+            .map(|entry| (entry.title, entry.start, entry.duration, entry.all_day))
+            .collect::<Vec<_>>();
+        let at = |hour: i64, min: i64| 1_699_920_000 + hour * 60 * 60 + min * 60;
+
+        // Entries follow each other from 09:00 in Berlin
+        let ics = Feed::new(&settings).unwrap().render(&months, 0);
+        assert!(ics.lines().all(|line| line.len() <= 76), "{ics}");
+        assert!(ics.contains("DESCRIPTION:Code review\\, part 1\\; see the ticket\\nand the wiki\r\n"), "{ics}");
+        assert!(ics.contains("CATEGORIES:meeting\r\n"));
+        let long = "A very long description that needs to be folded, because content lines are limited to 75 octets";
+        assert_eq!(events(&ics), [
+            ("Falco: Code review\\, part 1\\; see the ticket".to_string(), at(8, 0), 90 * 60, false),
+            ("Agami: Planning".to_string(), at(9, 30), 30 * 60, false),
+            (long.replace(',', "\\,"), at(10, 0), 15 * 60, false),
+            ("Sick".to_string(), at(24, 0), 0, true),
+        ]);
+
+        // Imported entries keep the time of their event, the others still follow each other
+        let ics = Feed::new(&settings).unwrap().with_times(FeedTimes::Imported).render(&months, 0);
+        let starts: Vec<_> = events(&ics).into_iter().map(|(_, start, _, _)| start).collect();
+        assert_eq!(starts, [at(8, 0), imported, at(9, 30), at(24, 0)]);
+
+        assert!(Feed::new(&TimesheetFeed { day_start: "9".into(), ..TimesheetFeed::default() }).is_err());
+    }
+
+    #[test]
+    fn keeps_uids_of_entries() {
+        let uids = |entries: &[DayEntry]| {
+            let mut days = vec![OneDay::default(); 30];
+            days[13].entries = entries.to_vec();
+            let ics = Feed::new(&TimesheetFeed::default()).unwrap().render(&[OneMonth { days, year: 2023, month: 11, created: 1, change_id: 1 }], 0);
+            ics.lines().filter_map(|line| line.strip_prefix("UID:")).map(str::to_string).collect::<Vec<_>>()
+        };
+        let imported = DayEntry { import_tags: vec!["040000008200E00074C5B7101A82E008".into()], ..entry("Agami", "Planning", 30, None) };
+        let entries = [entry("Falco", "Code review", 90, None), imported.clone(), entry("Falco", "Release", 60, None)];
+
+        let all = uids(&entries);
+        assert_eq!(all.len(), 3);
+        assert!(all.iter().all(|uid| uid.starts_with("20231114-") && uid.ends_with("@timesheets")));
+        // Removing the first entry keeps the UIDs of the others
+        assert_eq!(uids(&entries[1..]), all[1..]);
+        // Identical entries are numbered
+        let twice = uids(&[entries[0].clone(), entries[0].clone()]);
+        assert_eq!(twice, [all[0].clone(), all[0].replace("@timesheets", "-2@timesheets")]);
+        // The UID of an imported entry does not depend on its content
+        assert_eq!(uids(&[DayEntry { description: "Sprint planning".into(), ..imported }]), all[1..=1]);
+    }
+}
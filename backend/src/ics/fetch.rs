@@ -0,0 +1,591 @@
+//! Fetching of the configured calendar sources. Each source is cached separately.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use axum::http::{header, StatusCode};
+use ical::parser::ical::component::IcalCalendar;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::settings::{CalendarSource, SourceKind};
+use crate::storage::Storage;
+
+use super::{caldav, convert, parse, ConvertStats, DayFilter, Filter, ICSEntry, Mappings, TimeRange};
+use super::sync::next_sync;
+
+/// Fetch state of a source, kept in its timestamp cache
+#[derive(Serialize, Deserialize, Default)]
+pub(super) struct CacheMeta {
+    /// Unix timestamp in seconds of the last successful fetch, also if the calendar was not modified
+    pub(super) timestamp: u64,
+    /// Validators of the last response, sent with the next fetch
+    #[serde(default)]
+    pub(super) etag: Option<String>,
+    #[serde(default)]
+    pub(super) last_modified: Option<String>,
+    /// Unix timestamp in seconds of the last fetch, successful or not
+    #[serde(default)]
+    pub(super) last_attempt: u64,
+    /// Error of the last fetch, if it failed
+    #[serde(default)]
+    pub(super) last_error: Option<String>,
+    /// Failed fetches since the last successful one
+    #[serde(default)]
+    pub(super) failures: u32,
+    /// HTTP status of the last fetch, if the server responded
+    #[serde(default)]
+    pub(super) http_status: Option<u16>,
+    /// Conversion of the last successful fetch
+    #[serde(default)]
+    pub(super) stats: ConvertStats,
+}
+
+/// Sync state of one source
+#[derive(Serialize)]
+pub struct SourceStatus {
+    source: String,
+    /// Unix timestamps in seconds, `None` if there was none yet
+    last_attempt: Option<u64>,
+    last_success: Option<u64>,
+    /// When the background sync fetches the source next
+    next_sync: u64,
+    http_status: Option<u16>,
+    last_error: Option<String>,
+    /// Failed fetches since the last successful one
+    failures: u32,
+    /// Events in the calendar
+    events: usize,
+    /// Cached entries, after expanding recurring events and splitting multi-day events
+    entries: usize,
+    /// Cached entries that are dropped by the filters of the source
+    filtered: usize,
+    recurrence_failures: Vec<String>,
+    /// Unix timestamps in seconds of the time range of the cached entries
+    window_start: Option<i64>,
+    window_end: Option<i64>,
+}
+
+pub(super) async fn read_meta(storage: &Arc<dyn Storage>, source: &CalendarSource) -> CacheMeta {
+    let [_, _, ts_name] = cache_names(source);
+    storage.get_cache_json(&ts_name).await.ok().flatten().unwrap_or_default()
+}
+
+/// Result of refreshing one source
+#[derive(Serialize)]
+pub struct RefreshResult {
+    source: String,
+    status: RefreshStatus,
+    /// Unfiltered entries of the source
+    entries: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshStatus {
+    Updated,
+    NotModified,
+    Failed,
+}
+
+/// Cache names of a source: converted entries, parsed calendars and the fetch timestamp.
+/// Derived from name and url, so that changing the url of a source does not return stale entries.
+pub fn cache_names(source: &CalendarSource) -> [String; 3] {
+    let mut hasher = Sha256::new();
+    hasher.update(source.name.as_bytes());
+    hasher.update([0]);
+    hasher.update(source.url.as_bytes());
+    let id = hex::encode(&hasher.finalize()[..8]);
+    [format!("ics_{id}"), format!("ics_raw_{id}"), format!("ics_ts_{id}")]
+}
+
+/// Entries of all sources within `range` on the given `days`, filtered by the rules of their source and sorted by start.
+/// The project and tags of the entries are suggested by the defaults of their source and `mappings`.
+/// A source that fails is skipped, the request only fails if all sources fail.
+pub async fn fetch_sources(storage: &Arc<dyn Storage>, sources: &[CalendarSource], mappings: &Mappings, range: TimeRange, days: &DayFilter, now: u64) -> Result<Vec<ICSEntry>, (StatusCode, String)> {
+    let mut output = Vec::new();
+    let mut first_error = None;
+    let mut fetched = 0;
+    for source in sources {
+        let entries = match Filter::for_source(source) {
+            Ok(filter) => source_entries(storage, source, range, now).await.map(|entries| (filter, entries)),
+            Err(err) => Err((StatusCode::UNPROCESSABLE_ENTITY, err)),
+        };
+        match entries {
+            Ok((filter, mut entries)) => {
+                fetched += 1;
+                let unfiltered_entries = entries.len();
+                entries.retain(|entry| days.keeps(entry) && filter.keeps(entry));
+                tracing::info!("Fetch ICS {}: {:?}. Entries: {} ({})", source.name, days, entries.len(), unfiltered_entries);
+                output.extend(entries.into_iter().map(|entry| suggest(entry, source, mappings)));
+            }
+            Err(err) => {
+                tracing::error!("Fetch ICS {} failed: {}", source.name, err.1);
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+
+    match first_error {
+        Some(err) if fetched == 0 => Err(err),
+        _ => {
+            output.sort_by_key(|entry| entry.start);
+            Ok(output)
+        }
+    }
+}
+
+/// Entry of a source with the filter outcome of a preview
+#[derive(Serialize)]
+pub struct PreviewEntry {
+    #[serde(flatten)]
+    entry: ICSEntry,
+    kept: bool,
+}
+
+/// Cached entries of the sources within `range`, with whether `filter` keeps them, sorted by start.
+/// Sources that have not been fetched yet are skipped.
+pub async fn preview_sources(storage: &Arc<dyn Storage>, sources: &[CalendarSource], filter: &Filter, mappings: &Mappings, range: TimeRange) -> Result<Vec<PreviewEntry>, (StatusCode, String)> {
+    let mut output = Vec::new();
+    for source in sources {
+        if let Some(entries) = cached_entries(storage, source, range).await? {
+            output.extend(entries.into_iter().map(|entry| PreviewEntry { kept: filter.keeps(&entry), entry: suggest(entry, source, mappings) }));
+        }
+    }
+    output.sort_by_key(|preview| preview.entry.start);
+    Ok(output)
+}
+
+/// Tags an entry with the name of its source and suggests a project and tags
+fn suggest(entry: ICSEntry, source: &CalendarSource, mappings: &Mappings) -> ICSEntry {
+    let mut entry = ICSEntry {
+        source: source.name.clone(),
+        project: source.project.clone(),
+        tags: Some(&source.tag).filter(|tag| !tag.is_empty()).cloned().into_iter().collect(),
+        ..entry
+    };
+    mappings.apply(&mut entry);
+    entry
+}
+
+/// Fetches all sources now, regardless of their cache TTL and retry delay. Unchanged calendars are only converted again.
+pub async fn refresh_sources(storage: &Arc<dyn Storage>, sources: &[CalendarSource], range: TimeRange, now: u64) -> Vec<RefreshResult> {
+    let mut results = Vec::new();
+    for source in sources {
+        let result = match refresh_source(storage, source, range, now).await {
+            Ok((entries, modified)) => RefreshResult {
+                source: source.name.clone(),
+                status: if modified { RefreshStatus::Updated } else { RefreshStatus::NotModified },
+                entries: entries.len(),
+                error: None,
+            },
+            Err(err) => {
+                tracing::error!("Refresh ICS {} failed: {}", source.name, err.1);
+                RefreshResult { source: source.name.clone(), status: RefreshStatus::Failed, entries: 0, error: Some(err.1) }
+            }
+        };
+        results.push(result);
+    }
+    results
+}
+
+/// Sync state of all sources, taken from their caches
+pub async fn sources_status(storage: &Arc<dyn Storage>, sources: &[CalendarSource]) -> Vec<SourceStatus> {
+    let mut results = Vec::new();
+    for source in sources {
+        let [entries_name, _, _] = cache_names(source);
+        let meta = read_meta(storage, source).await;
+        let entries: Vec<ICSEntry> = storage.get_cache_json(&entries_name).await.ok().flatten().unwrap_or_default();
+        let converted = meta.timestamp > 0;
+        results.push(SourceStatus {
+            source: source.name.clone(),
+            last_attempt: (meta.last_attempt > 0).then_some(meta.last_attempt),
+            last_success: converted.then_some(meta.timestamp),
+            next_sync: next_sync(&meta, source),
+            http_status: meta.http_status,
+            last_error: meta.last_error,
+            failures: meta.failures,
+            events: meta.stats.events,
+            entries: entries.len(),
+            filtered: Filter::for_source(source).map_or(0, |filter| entries.iter().filter(|entry| !filter.keeps(entry)).count()),
+            recurrence_failures: meta.stats.recurrence_failures,
+            window_start: converted.then_some(meta.stats.window_start),
+            window_end: converted.then_some(meta.stats.window_end),
+        });
+    }
+    results
+}
+
+/// Unfiltered entries of a source within `range`, expanded from the cached calendar.
+/// The cache is kept up to date by the background sync, the source is only fetched here if nothing has been cached yet.
+/// CalDAV sources are queried for ranges outside of the cached one, without caching the result.
+async fn source_entries(storage: &Arc<dyn Storage>, source: &CalendarSource, range: TimeRange, now: u64) -> Result<Vec<ICSEntry>, (StatusCode, String)> {
+    if let Some(entries) = cached_entries(storage, source, range).await? {
+        return Ok(entries);
+    }
+    if source.kind == SourceKind::Caldav && read_meta(storage, source).await.timestamp > 0 {
+        let mut http_status = None;
+        let data = caldav::calendar_data(source, range, &mut http_status).await?;
+        return Ok(convert(&parse(&data), range).0);
+    }
+
+    refresh_source(storage, source, range, now).await.map(|(entries, _)| entries)
+}
+
+/// Unfiltered entries of a source within `range`, `None` if its calendar has not been cached yet.
+/// For CalDAV sources, only the queried range is cached.
+async fn cached_entries(storage: &Arc<dyn Storage>, source: &CalendarSource, range: TimeRange) -> Result<Option<Vec<ICSEntry>>, (StatusCode, String)> {
+    let [_, raw_name, _] = cache_names(source);
+    if source.kind == SourceKind::Caldav {
+        let cached = read_meta(storage, source).await.stats;
+        if range.start < cached.window_start || range.end > cached.window_end {
+            return Ok(None);
+        }
+    }
+
+    let cached = storage.get_cache_json::<Vec<IcalCalendar>>(&raw_name).await.ok().flatten();
+    Ok(cached.map(|calendars| convert(&calendars, range).0))
+}
+
+/// Lock of a source, by the name of its timestamp cache
+fn source_lock(ts_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> = OnceLock::new();
+    LOCKS.get_or_init(Mutex::default).lock().unwrap().entry(ts_name.to_string()).or_default().clone()
+}
+
+/// Fetches a source and updates its cache. Returns the unfiltered entries within `range` and whether the calendar changed.
+/// The outcome is recorded in the fetch state of the source. Refreshes of the same source, ie by the background sync
+/// and a request, run one after the other, so that they do not overwrite each other's cache and fetch state.
+pub(super) async fn refresh_source(storage: &Arc<dyn Storage>, source: &CalendarSource, range: TimeRange, now: u64) -> Result<(Vec<ICSEntry>, bool), (StatusCode, String)> {
+    let [_, _, ts_name] = cache_names(source);
+    let lock = source_lock(&ts_name);
+    let _guard = lock.lock().await;
+
+    let mut meta = read_meta(storage, source).await;
+    let result = update_source(storage, source, range, &mut meta).await;
+    meta.last_attempt = now;
+    match &result {
+        Ok(_) => {
+            meta.timestamp = now;
+            meta.last_error = None;
+            meta.failures = 0;
+        }
+        Err(err) => {
+            meta.last_error = Some(err.1.clone());
+            meta.failures += 1;
+        }
+    }
+    storage.set_cache_json(&ts_name, &meta).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    result
+}
+
+/// Downloads and converts a source and caches the result. The request is conditional if the parsed calendar of the
+/// previous fetch is cached. If the calendar has not been modified, that one is converted again, because recurring
+/// events are only expanded up to the time of conversion.
+async fn update_source(storage: &Arc<dyn Storage>, source: &CalendarSource, range: TimeRange, meta: &mut CacheMeta) -> Result<(Vec<ICSEntry>, bool), (StatusCode, String)> {
+    let [entries_name, raw_name, _] = cache_names(source);
+
+    let cached: Option<Vec<IcalCalendar>> = storage.get_cache_json(&raw_name).await.ok().flatten();
+    let conditional = cached.is_some();
+
+    let (calendars, response) = match (download(source, meta, conditional, range).await?, cached) {
+        (Some(response), _) => (parse(&response.body), Some(response)),
+        (None, Some(calendars)) => (calendars, None),
+        (None, None) => return Err((StatusCode::NOT_FOUND, "Calendar not modified, but nothing cached".to_string())),
+    };
+    let (output, stats) = convert(&calendars, range);
+
+    async {
+        if response.is_some() {
+            storage.set_cache_json(&raw_name, &calendars).await?;
+        }
+        storage.set_cache_json(&entries_name, &output).await
+    }.await.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    // Only now the cached calendar belongs to these validators
+    meta.stats = stats;
+    let modified = response.is_some();
+    if let Some(response) = response {
+        meta.etag = response.etag;
+        meta.last_modified = response.last_modified;
+    }
+
+    if modified {
+        tracing::info!("Fetch ICS {} successful. Entries {}", source.name, output.len());
+    } else {
+        tracing::info!("Fetch ICS {}: Not modified. Entries {}", source.name, output.len());
+    }
+    Ok((output, modified))
+}
+
+struct Download {
+    body: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Where the calendar of a source is read from
+#[derive(Debug, PartialEq, Eq)]
+pub enum Location {
+    File(PathBuf),
+    Http(reqwest::Url),
+}
+
+/// Directory of the calendar files that `file://` sources may read (`ICS_FILE_DIR`)
+static FILE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Allows `file://` sources for the files within `dir`. Without, `file://` urls are rejected,
+/// so that the settings can not be used to read arbitrary files of the server.
+pub fn allow_files_in(dir: PathBuf) {
+    if FILE_DIR.set(dir).is_err() {
+        tracing::warn!("Directory of calendar files is already set");
+    }
+}
+
+/// The location of the calendar of `source`. `webcal://` is fetched with https,
+/// plain `http://` only if the source allows it, `file://` only within the directory set by [`allow_files_in`].
+pub fn location(source: &CalendarSource) -> Result<Location, String> {
+    let invalid = |err: &dyn std::fmt::Display| format!("Calendar {}: Invalid url: {}", source.name, err);
+    let mut url = reqwest::Url::parse(&source.url).map_err(|err| invalid(&err))?;
+    match url.scheme() {
+        "file" => {
+            let path = url.to_file_path().map_err(|()| invalid(&"Not an absolute path"))?;
+            return match FILE_DIR.get() {
+                Some(dir) if path.starts_with(dir) => Ok(Location::File(path)),
+                Some(dir) => Err(invalid(&format!("Only files in {} are read", dir.display()))),
+                None => Err(invalid(&"Calendar files are only read from the directory set by ICS_FILE_DIR")),
+            };
+        }
+        "https" => {}
+        "http" if source.allow_http => {}
+        "http" => return Err(invalid(&"Plain http is only used with allow_http")),
+        // Both are subscription links to an https calendar
+        "webcal" | "webcals" => {
+            let https = format!("https{}", &source.url[url.scheme().len()..]);
+            url = reqwest::Url::parse(&https).map_err(|err| invalid(&err))?;
+        }
+        scheme => return Err(invalid(&format!("Unsupported scheme {scheme}"))),
+    }
+    Ok(Location::Http(url))
+}
+
+/// HTTP client for the requests of a source
+pub(super) fn client(source: &CalendarSource) -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .https_only(!source.allow_http)
+        .timeout(Duration::from_secs(30))
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+/// Adds the credentials of a source to a request
+pub(super) fn authorize(request: reqwest::RequestBuilder, source: &CalendarSource) -> reqwest::RequestBuilder {
+    if !source.token.is_empty() {
+        request.bearer_auth(&source.token)
+    } else if !source.username.is_empty() {
+        request.basic_auth(&source.username, Some(&source.password))
+    } else {
+        request
+    }
+}
+
+/// Reads the calendar of a source and records the HTTP status in `meta`. CalDAV sources are queried for `range`.
+/// A `conditional` fetch sends the validators of `meta`, `None` is returned if the calendar has not been modified since.
+async fn download(source: &CalendarSource, meta: &mut CacheMeta, conditional: bool, range: TimeRange) -> Result<Option<Download>, (StatusCode, String)> {
+    meta.http_status = None;
+    if source.kind == SourceKind::Caldav {
+        let body = caldav::calendar_data(source, range, &mut meta.http_status).await?;
+        return Ok(Some(Download { body, etag: None, last_modified: None }));
+    }
+    let validators = conditional.then_some(&*meta);
+    let url = match location(source).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))? {
+        Location::File(path) => return read_file(source, &path, validators).await,
+        Location::Http(url) => url,
+    };
+    let (buf, status, etag, last_modified) = async {
+        let mut request = authorize(client(source)?.get(url), source);
+        if let Some(etag) = validators.and_then(|meta| meta.etag.as_ref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = validators.and_then(|meta| meta.last_modified.as_ref()) {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let res = request.send().await?;
+
+        let status = res.status();
+        let header_value = |name| res.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let etag = header_value(header::ETAG);
+        let last_modified = header_value(header::LAST_MODIFIED);
+        let buf = res.text().await?;
+        Ok::<_, reqwest::Error>((buf, status, etag, last_modified))
+    }.await.map_err(|err| {
+        tracing::error!("Failed to fetch ICS {} from {}: {:?}", source.name, source.url, err);
+        (StatusCode::NOT_FOUND, err.to_string())
+    })?;
+
+    meta.http_status = Some(status.as_u16());
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if status != 200 {
+        tracing::info!("Fetch ICS {} failed {} {}", source.name, status, &buf);
+        return Err((status, buf));
+    }
+    Ok(Some(Download { body: buf, etag, last_modified }))
+}
+
+/// Reads a local calendar file. Its modification time, as unix timestamp, is the `Last-Modified` validator.
+async fn read_file(source: &CalendarSource, path: &Path, validators: Option<&CacheMeta>) -> Result<Option<Download>, (StatusCode, String)> {
+    let failed = |err: std::io::Error| {
+        tracing::error!("Failed to read ICS {} from {}: {:?}", source.name, path.display(), err);
+        (StatusCode::NOT_FOUND, err.to_string())
+    };
+    // Symbolic links must not lead out of the directory either
+    let dir = FILE_DIR.get().ok_or_else(|| (StatusCode::UNPROCESSABLE_ENTITY, "Calendar files are not allowed".to_string()))?;
+    let real_dir = tokio::fs::canonicalize(dir).await.map_err(failed)?;
+    if !tokio::fs::canonicalize(path).await.map_err(failed)?.starts_with(real_dir) {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, format!("Calendar {}: Only files in {} are read", source.name, dir.display())));
+    }
+    let modified = tokio::fs::metadata(path).await.map_err(failed)?
+        .modified().ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs().to_string());
+    if modified.is_some() && validators.and_then(|meta| meta.last_modified.as_ref()) == modified.as_ref() {
+        return Ok(None);
+    }
+    let body = tokio::fs::read_to_string(path).await.map_err(failed)?;
+    Ok(Some(Download { body, etag: None, last_modified: modified }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{EventMatch, FilterRule};
+    use crate::storage::{self, RetentionPolicy, StorageConfig, StorageKind};
+
+    /// A calendar with one hour events, given as (uid, summary, start)
+    fn calendar(events: &[(&str, &str, &str)]) -> Vec<IcalCalendar> {
+        let events = events.iter()
+            .map(|(uid, summary, start)| format!("BEGIN:VEVENT\r\nUID:{uid}\r\nSUMMARY:{summary}\r\nDTSTART:{start}\r\nDURATION:PT1H\r\nEND:VEVENT\r\n"))
+            .collect::<Vec<_>>()
+            .concat();
+        parse(&format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{events}END:VCALENDAR\r\n"))
+    }
+
+    /// File storage in an empty temporary directory
+    async fn temp_storage(name: &str) -> (std::path::PathBuf, Arc<dyn Storage>) {
+        let dir = std::env::temp_dir().join(format!("timesheet-ics-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = storage::open(&StorageConfig {
+            kind: StorageKind::File,
+            data_dir: dir.clone(),
+            database_path: dir.join("timesheets.sqlite"),
+            retention: RetentionPolicy::default(),
+        }).await.unwrap();
+        (dir, storage)
+    }
+
+    #[tokio::test]
+    async fn merges_cached_sources() {
+        let (dir, storage) = temp_storage("sources").await;
+
+        // 2023-11-14 12:00 UTC
+        let now = 1_699_963_200;
+        let range = TimeRange { start: 1_699_963_200 - 24 * 60 * 60, end: 1_699_963_200 + 24 * 60 * 60 };
+        let work = CalendarSource { name: "Work".into(), url: "https://outlook/work.ics".into(), filter: vec!["Lunch".into()], project: "Falco".into(), ..Default::default() };
+        // Fetched two days ago: requests answer from the cache, refreshing is left to the background sync
+        let team = CalendarSource { name: "Team".into(), url: "https://team/team.ics".into(), tag: "meeting".into(), ..Default::default() };
+        let work_calendar = calendar(&[("a", "Standup", "20231114T140000Z"), ("b", "Lunch", "20231114T120000Z"), ("d", "Planning", "20231120T090000Z")]);
+        let team_calendar = calendar(&[("c", "Lunch", "20231114T130000Z")]);
+        for (source, calendars, fetched) in [(&work, work_calendar, now), (&team, team_calendar, now - 2 * 24 * 60 * 60)] {
+            let [entries_name, raw_name, ts_name] = cache_names(source);
+            storage.set_cache_json(&raw_name, &calendars).await.unwrap();
+            storage.set_cache_json(&entries_name, &convert(&calendars, range).0).await.unwrap();
+            storage.set_cache_json(&ts_name, &CacheMeta { timestamp: fetched, ..Default::default() }).await.unwrap();
+        }
+
+        let mappings = Mappings::new(&[]).unwrap();
+        let output = fetch_sources(&storage, &[work.clone(), team.clone()], &mappings, range, &DayFilter::default(), now).await.unwrap();
+        let output: Vec<_> = output.iter().map(|entry| (entry.uid.as_str(), entry.source.as_str(), entry.project.as_str(), entry.tags.clone())).collect();
+        // The filter of a source only applies to its own entries
+        assert_eq!(output, [("c", "Team", "", vec!["meeting".to_string()]), ("a", "Work", "Falco", vec![])]);
+
+        // Other ranges are expanded from the cached calendars
+        let next_week = TimeRange { start: range.end, end: range.end + 7 * 24 * 60 * 60 };
+        let output = fetch_sources(&storage, &[work.clone(), team.clone()], &mappings, next_week, &DayFilter::default(), now).await.unwrap();
+        assert_eq!(output.iter().map(|entry| entry.uid.as_str()).collect::<Vec<_>>(), ["d"]);
+
+        // The preview only applies the given rules
+        let standup = FilterRule { condition: EventMatch { pattern: "Standup".into(), ..Default::default() }, ..Default::default() };
+        let preview = preview_sources(&storage, &[work.clone(), team.clone()], &Filter::new(&[standup]).unwrap(), &mappings, range).await.unwrap();
+        let preview: Vec<_> = preview.iter().map(|preview| (preview.entry.uid.as_str(), preview.kept)).collect();
+        assert_eq!(preview, [("b", true), ("c", true), ("a", false)]);
+
+        let status = sources_status(&storage, &[work.clone(), team.clone()]).await;
+        let status: Vec<_> = status.iter().map(|status| (status.last_success, status.entries, status.filtered, status.next_sync)).collect();
+        assert_eq!(status, [(Some(now), 2, 1, now + 24 * 60 * 60), (Some(now - 2 * 24 * 60 * 60), 1, 0, now - 24 * 60 * 60)]);
+
+        // A different url does not use the cache of the old one
+        assert_ne!(cache_names(&work), cache_names(&CalendarSource { url: "https://outlook/other.ics".into(), ..work }));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locations() {
+        let location = |url: &str, allow_http| location(&CalendarSource { name: "Test".into(), url: url.into(), allow_http, ..Default::default() });
+        let http = |url: &str| Ok(Location::Http(reqwest::Url::parse(url).unwrap()));
+
+        assert_eq!(location("https://calendar.google.com/calendar/ical/basic.ics", false), http("https://calendar.google.com/calendar/ical/basic.ics"));
+        assert_eq!(location("webcal://p01-caldav.icloud.com/published/2/abc", false), http("https://p01-caldav.icloud.com/published/2/abc"));
+        // Local files only within the configured directory
+        allow_files_in(std::env::temp_dir());
+        let file = std::env::temp_dir().join("calendar.ics");
+        assert_eq!(location(reqwest::Url::from_file_path(&file).unwrap().as_str(), false), Ok(Location::File(file)));
+        assert!(location("file:///etc/passwd", false).is_err());
+        assert!(location(&format!("file://{}/../etc/passwd", std::env::temp_dir().display()), false).is_err());
+        // Plain http only on request
+        assert!(location("http://nextcloud.local/remote.php/dav/calendars/me/work?export", false).is_err());
+        assert_eq!(location("http://nextcloud.local/calendar.ics", true), http("http://nextcloud.local/calendar.ics"));
+        assert!(location("ftp://example.com/calendar.ics", true).is_err());
+        assert!(location("calendar.ics", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_local_files() {
+        allow_files_in(std::env::temp_dir());
+        let (dir, storage) = temp_storage("files").await;
+        let path = dir.join("calendar.ics");
+        std::fs::write(&path, "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:a\r\nSUMMARY:Standup\r\nDTSTART:20231114T140000Z\r\nDURATION:PT1H\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n").unwrap();
+        let source = CalendarSource { name: "Local".into(), url: reqwest::Url::from_file_path(&path).unwrap().to_string(), ..Default::default() };
+
+        let now = 1_699_963_200;
+        let range = TimeRange { start: 1_699_963_200 - 24 * 60 * 60, end: 1_699_963_200 + 24 * 60 * 60 };
+        let (entries, modified) = refresh_source(&storage, &source, range, now).await.unwrap();
+        assert_eq!((entries.len(), modified), (1, true));
+        // Unchanged since, by its modification time
+        let (entries, modified) = refresh_source(&storage, &source, range, now + 60).await.unwrap();
+        assert_eq!((entries.len(), modified), (1, false));
+
+        // Concurrent refreshes both count
+        std::fs::remove_file(&path).unwrap();
+        let (first, second) = tokio::join!(refresh_source(&storage, &source, range, now + 120), refresh_source(&storage, &source, range, now + 120));
+        assert!(first.is_err() && second.is_err());
+        assert_eq!(read_meta(&storage, &source).await.failures, 2);
+
+        // Not through a link to a file outside of the directory
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"), &path).unwrap();
+            let result = refresh_source(&storage, &source, range, now + 180).await;
+            assert!(matches!(result, Err((StatusCode::UNPROCESSABLE_ENTITY, _))));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Filter rules that decide which ICS entries are kept, and mapping rules that suggest their project and tags
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Timelike, Weekday};
+use regex::{Regex, RegexBuilder};
+
+use crate::settings::{CalendarSource, EventMatch, FilterAction, FilterField, FilterRule, MappingRule, PatternSyntax};
+
+use super::{ICSEntry, MatchedRule};
+
+/// Compiled filter rules
+pub struct Filter {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+}
+
+struct Matcher {
+    field: FilterField,
+    pattern: Regex,
+    weekdays: Vec<Weekday>,
+    times: Option<(NaiveTime, NaiveTime)>,
+    zone: Option<chrono_tz::Tz>,
+}
+
+impl Filter {
+    pub fn new(rules: &[FilterRule]) -> Result<Self, String> {
+        let mut filter = Self { include: Vec::new(), exclude: Vec::new() };
+        for (index, rule) in rules.iter().enumerate() {
+            let matcher = Matcher::new(&rule.condition).map_err(|err| format!("Filter rule {}: {}", index + 1, err))?;
+            match rule.action {
+                FilterAction::Include => filter.include.push(matcher),
+                FilterAction::Exclude => filter.exclude.push(matcher),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// The rules of a source. Its plain `filter` strings exclude entries with a title that contains them.
+    pub fn for_source(source: &CalendarSource) -> Result<Self, String> {
+        let legacy = source.filter.iter().map(|pattern| FilterRule {
+            condition: EventMatch { pattern: pattern.clone(), ..Default::default() },
+            ..Default::default()
+        });
+        let rules: Vec<_> = legacy.chain(source.rules.iter().cloned()).collect();
+        Self::new(&rules).map_err(|err| format!("Calendar {}: {}", source.name, err))
+    }
+
+    pub fn keeps(&self, entry: &ICSEntry) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|matcher| matcher.matches(entry)))
+            && !self.exclude.iter().any(|matcher| matcher.matches(entry))
+    }
+}
+
+/// Compiled mapping rules
+pub struct Mappings {
+    rules: Vec<(Matcher, MappingRule)>,
+}
+
+impl Mappings {
+    pub fn new(rules: &[MappingRule]) -> Result<Self, String> {
+        let rules = rules.iter().enumerate()
+            .map(|(index, rule)| {
+                let matcher = Matcher::new(&rule.condition).map_err(|err| format!("Mapping rule {}: {}", index + 1, err))?;
+                Ok((matcher, rule.clone()))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Applies the first matching rule to the suggested project and tags of the entry
+    pub fn apply(&self, entry: &mut ICSEntry) {
+        let Some((index, (_, rule))) = self.rules.iter().enumerate().find(|(_, (matcher, _))| matcher.matches(entry)) else {
+            return;
+        };
+        if !rule.project.is_empty() {
+            entry.project.clone_from(&rule.project);
+        }
+        for tag in &rule.tags {
+            if !entry.tags.contains(tag) {
+                entry.tags.push(tag.clone());
+            }
+        }
+        entry.matched_rule = Some(MatchedRule { index, name: rule.name.clone() });
+    }
+}
+
+impl Matcher {
+    fn new(rule: &EventMatch) -> Result<Self, String> {
+        let pattern = match rule.syntax {
+            PatternSyntax::Contains => regex::escape(&rule.pattern),
+            PatternSyntax::Glob => glob_to_regex(&rule.pattern),
+            PatternSyntax::Regex => rule.pattern.clone(),
+        };
+        let pattern = RegexBuilder::new(&pattern)
+            .case_insensitive(rule.case_insensitive)
+            .build()
+            .map_err(|err| err.to_string())?;
+
+        let time = |value: &Option<String>, default: NaiveTime| value.as_ref().map_or(Ok(default), |value| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|err| format!("Invalid time {value}: {err}"))
+        });
+        let times = match (&rule.time_from, &rule.time_to) {
+            (None, None) => None,
+            (from, to) => Some((time(from, NaiveTime::MIN)?, time(to, NaiveTime::MIN)?)),
+        };
+        let zone = rule.time_zone.as_deref()
+            .map(|name| chrono_tz::Tz::from_str(name).map_err(|err| format!("Invalid time zone {name}: {err}")))
+            .transpose()?;
+
+        Ok(Self { field: rule.field, pattern, weekdays: rule.weekdays.clone(), times, zone })
+    }
+
+    fn matches(&self, entry: &ICSEntry) -> bool {
+        if !self.weekdays.is_empty() || self.times.is_some() {
+            let start = DateTime::from_timestamp(entry.start, 0).unwrap_or_default().naive_utc();
+            // All-day entries are on their date, independent of the time zone
+            let local = match self.zone {
+                Some(zone) if !entry.all_day => zone.from_utc_datetime(&start).naive_local(),
+                _ => start,
+            };
+            if !self.weekdays.is_empty() && !self.weekdays.contains(&local.weekday()) {
+                return false;
+            }
+            if let Some((from, to)) = self.times {
+                let time = local.time().with_second(0).unwrap_or_default();
+                // `to` before `from` wraps around midnight, `00:00` as end is the end of the day
+                let in_range = if from < to { from <= time && time < to } else { from <= time || time < to };
+                if !in_range {
+                    return false;
+                }
+            }
+        }
+
+        match self.field {
+            FilterField::Title => self.pattern.is_match(&entry.title),
+            FilterField::Description => self.pattern.is_match(&entry.desc),
+            FilterField::Organizer => self.pattern.is_match(&entry.organizer),
+            FilterField::Location => self.pattern.is_match(&entry.location),
+            FilterField::Categories => entry.categories.iter().any(|category| self.pattern.is_match(category)),
+            FilterField::BusyStatus => self.pattern.is_match(&entry.busy_status),
+            FilterField::Status => self.pattern.is_match(&entry.status),
+            FilterField::Transp => self.pattern.is_match(&entry.transp),
+            FilterField::OnlineMeetingUrl => self.pattern.is_match(&entry.online_meeting_url),
+        }
+    }
+}
+
+/// `*` matches any text and `?` one character, the whole value has to match
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(title: &str, start: i64) -> ICSEntry {
+        ICSEntry { title: title.into(), start, categories: vec!["Team".into(), "Internal".into()], busy_status: "TENTATIVE".into(), ..Default::default() }
+    }
+
+    #[test]
+    fn rules() {
+        // Monday 2023-05-15 09:30 UTC, 11:30 in Berlin
+        let monday = 1_684_143_000;
+        let friday = monday + 4 * 24 * 60 * 60;
+        let rule = |action, field, syntax, pattern: &str| FilterRule { action, condition: EventMatch { field, syntax, pattern: pattern.into(), ..Default::default() } };
+        let keeps = |rules: &[FilterRule], entry: &ICSEntry| Filter::new(rules).unwrap().keeps(entry);
+
+        let standup = entry("Daily Standup", monday);
+        assert!(!keeps(&[rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Contains, "Standup")], &standup));
+        assert!(keeps(&[rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Contains, "standup")], &standup));
+        let mut case_insensitive = rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Contains, "standup");
+        case_insensitive.condition.case_insensitive = true;
+        assert!(!keeps(&[case_insensitive], &standup));
+        assert!(!keeps(&[rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Glob, "Daily*")], &standup));
+        // Globs match the whole title
+        assert!(keeps(&[rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Glob, "Stand*")], &standup));
+        assert!(!keeps(&[rule(FilterAction::Exclude, FilterField::Categories, PatternSyntax::Regex, "^Intern")], &standup));
+        assert!(!keeps(&[rule(FilterAction::Exclude, FilterField::BusyStatus, PatternSyntax::Regex, "FREE|TENTATIVE")], &standup));
+
+        // Include rules drop everything that matches none of them
+        let include = [rule(FilterAction::Include, FilterField::Title, PatternSyntax::Regex, "^(Daily|Weekly) ")];
+        assert!(keeps(&include, &standup));
+        assert!(!keeps(&include, &entry("Lunch", monday)));
+
+        // Mornings in Berlin, only on Fridays
+        let morning = FilterRule {
+            action: FilterAction::Exclude,
+            condition: EventMatch {
+                weekdays: vec![Weekday::Fri],
+                time_from: Some("08:00".into()),
+                time_to: Some("12:00".into()),
+                time_zone: Some("Europe/Berlin".into()),
+                ..Default::default()
+            },
+        };
+        assert!(keeps(std::slice::from_ref(&morning), &standup));
+        assert!(!keeps(std::slice::from_ref(&morning), &entry("Daily Standup", friday)));
+        assert!(keeps(std::slice::from_ref(&morning), &entry("Daily Standup", friday + 3 * 60 * 60)));
+
+        assert!(Filter::new(&[rule(FilterAction::Exclude, FilterField::Title, PatternSyntax::Regex, "(")]).is_err());
+        assert!(Filter::new(&[FilterRule { condition: EventMatch { time_from: Some("25:00".into()), ..Default::default() }, ..Default::default() }]).is_err());
+    }
+
+    #[test]
+    fn mappings() {
+        let mapping = |name: &str, pattern: &str, project: &str, tags: &[&str]| MappingRule {
+            name: name.into(),
+            condition: EventMatch { syntax: PatternSyntax::Regex, pattern: pattern.into(), case_insensitive: true, ..Default::default() },
+            project: project.into(),
+            tags: tags.iter().map(ToString::to_string).collect(),
+        };
+        let mappings = Mappings::new(&[
+            mapping("Falco", "falco", "Falco", &["meeting"]),
+            mapping("", "review", "", &["review"]),
+            mapping("Reviews", "review", "Rowi", &[]),
+        ]).unwrap();
+        let suggest = |title: &str| {
+            let mut entry = ICSEntry { project: "Agami".into(), tags: vec!["meeting".into()], ..entry(title, 0) };
+            mappings.apply(&mut entry);
+            (entry.project, entry.tags, entry.matched_rule.map(|rule| (rule.index, rule.name)))
+        };
+
+        assert_eq!(suggest("FALCO sync"), ("Falco".to_string(), vec!["meeting".to_string()], Some((0, "Falco".to_string()))));
+        // Only the first matching rule applies, the default project stays without a project of the rule
+        assert_eq!(suggest("Code review"), ("Agami".to_string(), vec!["meeting".to_string(), "review".to_string()], Some((1, String::new()))));
+        assert_eq!(suggest("Lunch"), ("Agami".to_string(), vec!["meeting".to_string()], None));
+
+        // Conditions are part of the rule object in the settings
+        let rule: MappingRule = serde_json::from_str(r#"{"name": "Falco", "syntax": "regex", "pattern": "falco", "case_insensitive": true, "project": "Falco", "tags": ["meeting"]}"#).unwrap();
+        assert_eq!(rule, mapping("Falco", "falco", "Falco", &["meeting"]));
+    }
+}
@@ -0,0 +1,643 @@
+//! Conversion of ICS calendars (ie a published Outlook calendar) into entries for the timesheet frontend
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use ical::parser::ical::component::{IcalCalendar, IcalEvent};
+use ical::property::Property;
+use rrule::{RRule, RRuleError, RRuleSet, Tz};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::IcsWindow;
+
+mod caldav;
+mod feed;
+mod fetch;
+mod filter;
+pub mod sync;
+mod timezone;
+mod windows_zones;
+
+pub use fetch::{allow_files_in, cache_names, fetch_sources, location, preview_sources, refresh_sources, sources_status, Location, PreviewEntry, RefreshResult, SourceStatus};
+pub use feed::Feed;
+pub use filter::{Filter, Mappings};
+
+use timezone::{TimeZones, Zone};
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ICSEntry {
+    desc: String,
+    uid: String,
+    title: String,
+    /// Unix timestamp in seconds
+    start: i64,
+    /// Duration in seconds
+    duration: i64,
+    confirmed: bool,
+    /// Out of office
+    oof: bool,
+    /// The entry covers the whole day of `start`, which is midnight UTC of that date.
+    /// All-day entries have no duration, multi-day events are split into one entry per day.
+    #[serde(default)]
+    all_day: bool,
+    /// Name of the calendar source
+    #[serde(default)]
+    source: String,
+    /// Suggested project: by the matching mapping rule, or the default of the calendar source. Empty if none.
+    #[serde(default)]
+    project: String,
+    /// Suggested tags: the default of the calendar source and those of the matching mapping rule
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Mapping rule that matched the entry
+    #[serde(default)]
+    matched_rule: Option<MatchedRule>,
+    #[serde(default)]
+    location: String,
+    /// `Name <email>`, or only one of them
+    #[serde(default)]
+    organizer: String,
+    #[serde(default)]
+    categories: Vec<String>,
+    /// `X-MICROSOFT-CDO-BUSYSTATUS`, ie `BUSY` or `OOF`
+    #[serde(default)]
+    busy_status: String,
+    /// Number of `ATTENDEE`s
+    #[serde(default)]
+    attendees: usize,
+    /// `STATUS`: `TENTATIVE` or `CONFIRMED`. Cancelled events are not converted.
+    #[serde(default)]
+    status: String,
+    /// `TRANSP`: `OPAQUE`, or `TRANSPARENT` for events that do not block time
+    #[serde(default)]
+    transp: String,
+    /// Link to join the Teams, Zoom or Google Meet meeting
+    #[serde(default)]
+    online_meeting_url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MatchedRule {
+    /// Position in the mapping rules of the settings
+    index: usize,
+    name: String,
+}
+
+/// Parses an ICS document. Calendars that can not be parsed are skipped.
+pub fn parse(ics: &str) -> Vec<IcalCalendar> {
+    ical::IcalParser::new(ics.as_bytes()).flatten().collect()
+}
+
+/// The days of the entries to return. Days are cut at midnight in `zone`, the time zone of the user.
+/// All-day entries keep their date.
+#[derive(Clone, Copy, Debug)]
+pub struct DayFilter {
+    pub zone: chrono_tz::Tz,
+    /// First and last day (inclusive)
+    pub first: Option<NaiveDate>,
+    pub last: Option<NaiveDate>,
+    /// Month (1 to 12) and day of the month, in any year
+    pub month: Option<u64>,
+    pub day: Option<u64>,
+}
+
+impl Default for DayFilter {
+    fn default() -> Self {
+        Self { zone: chrono_tz::UTC, first: None, last: None, month: None, day: None }
+    }
+}
+
+impl DayFilter {
+    /// Unix timestamp of the start of `date` in the zone
+    pub fn start_of(&self, date: NaiveDate) -> i64 {
+        let midnight = date.and_time(NaiveTime::MIN);
+        // Some zones skip midnight when DST starts
+        self.zone.from_local_datetime(&midnight).earliest()
+            .or_else(|| self.zone.from_local_datetime(&(midnight + Duration::hours(1))).earliest())
+            .map_or_else(|| midnight.and_utc().timestamp(), |start| start.timestamp())
+    }
+
+    /// The day of an entry. All-day entries start at midnight UTC of their date.
+    fn date(&self, entry: &ICSEntry) -> Option<NaiveDate> {
+        let start = DateTime::from_timestamp(entry.start, 0)?;
+        Some(if entry.all_day { start.date_naive() } else { start.with_timezone(&self.zone).date_naive() })
+    }
+
+    pub fn keeps(&self, entry: &ICSEntry) -> bool {
+        self.date(entry).is_some_and(|date| {
+            self.first.is_none_or(|first| date >= first)
+                && self.last.is_none_or(|last| date <= last)
+                && self.month.is_none_or(|month| u64::from(date.month()) == month)
+                && self.day.is_none_or(|day| u64::from(date.day()) == day)
+        })
+    }
+}
+
+/// `UNTIL` of a recurrence rule is in UTC, but rules are expanded on the wall clock time of the event
+fn localize_until(rule: &str, zone: &Zone) -> String {
+    rule.split(';')
+        .map(|part| {
+            let until = part.strip_prefix("UNTIL=")
+                .and_then(|value| value.strip_suffix('Z'))
+                .and_then(|value| NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok());
+            until.map_or_else(|| part.to_string(), |until| format!("UNTIL={}Z", zone.to_local(until).format("%Y%m%dT%H%M%S")))
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Parses a `DURATION` value like `PT1H30M`, `P1D` or `-PT15M` into seconds
+fn parse_duration(value: &str) -> Option<i64> {
+    let (sign, value) = match value.as_bytes().first()? {
+        b'-' => (-1, &value[1..]),
+        b'+' => (1, &value[1..]),
+        _ => (1, value),
+    };
+    let value = value.strip_prefix('P')?;
+    let mut seconds = 0;
+    let mut number = String::new();
+    let mut time = false;
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if number.is_empty() && !time => time = true,
+            _ => {
+                let unit = match (c, time) {
+                    ('W', false) => 7 * 24 * 60 * 60,
+                    ('D', false) => 24 * 60 * 60,
+                    ('H', true) => 60 * 60,
+                    ('M', true) => 60,
+                    ('S', true) => 1,
+                    _ => return None,
+                };
+                seconds += number.parse::<i64>().ok()? * unit;
+                number.clear();
+            }
+        }
+    }
+    number.is_empty().then_some(sign * seconds)
+}
+
+/// Splits an occurrence that starts at `local_start` (wall clock time in `zone`) into one entry per day.
+/// Timed events are split at midnight in `zone`.
+fn split_days(entry: &ICSEntry, local_start: NaiveDateTime, zone: &Zone) -> Vec<ICSEntry> {
+    const DAY: i64 = 24 * 60 * 60;
+    let utc = |local: NaiveDateTime| Utc.from_utc_datetime(&zone.to_utc(local)).timestamp();
+
+    if entry.all_day {
+        // DTEND is exclusive. Rounded, because a DST change in between shortens or extends a day by an hour.
+        let days = ((entry.duration + DAY / 2) / DAY).max(1);
+        return (0..days)
+            .map(|day| ICSEntry {
+                start: Utc.from_utc_datetime(&(local_start.date() + Duration::days(day)).and_time(NaiveTime::MIN)).timestamp(),
+                duration: 0,
+                ..entry.clone()
+            })
+            .collect();
+    }
+    if entry.duration <= 0 {
+        return vec![entry.clone()];
+    }
+
+    let local_end = zone.to_local(zone.to_utc(local_start) + Duration::seconds(entry.duration));
+    let mut pieces = Vec::new();
+    let mut from = local_start;
+    while from < local_end {
+        let next_day = (from.date() + Duration::days(1)).and_time(NaiveTime::MIN);
+        let to = next_day.min(local_end);
+        pieces.push(ICSEntry { start: utc(from), duration: utc(to) - utc(from), ..entry.clone() });
+        from = to;
+    }
+    pieces
+}
+
+/// `Name <email>` of an `ORGANIZER` or `ATTENDEE` property
+fn organizer(prop: &Property) -> String {
+    let value = prop.value.as_deref().unwrap_or_default();
+    let email = match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+        _ => value,
+    };
+    let name = prop.params.iter().flatten()
+        .find(|(name, _)| name == "CN")
+        .and_then(|(_, values)| values.first())
+        .map(|name| name.trim_matches('"'));
+    match name {
+        Some(name) if !email.is_empty() => format!("{name} <{email}>"),
+        Some(name) => name.to_string(),
+        None => email.to_string(),
+    }
+}
+
+/// Join links of online meetings in descriptions, for calendars without a property for them
+const MEETING_URLS: [&str; 4] = [
+    "https://teams.microsoft.com/l/meetup-join/",
+    "https://teams.live.com/meet/",
+    "https://meet.google.com/",
+    ".zoom.us/j/",
+];
+
+/// The first join link of an online meeting in `text`
+fn meeting_url(text: &str) -> Option<String> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\\'))
+        .map(|word| word.trim_end_matches(['.', ',', ')', ']']))
+        .find(|word| word.starts_with("https://") && MEETING_URLS.iter().any(|url| word.contains(url)))
+        .map(str::to_string)
+}
+
+/// Adds entries that are not part of the output yet, by UID and start. Parallel meetings have different UIDs.
+fn push_unique(output: &mut Vec<ICSEntry>, seen: &mut HashSet<(String, i64)>, entries: Vec<ICSEntry>) {
+    for entry in entries {
+        if seen.insert((entry.uid.clone(), entry.start)) {
+            output.push(entry);
+        }
+    }
+}
+
+/// Occurrences of recurring events that are replaced by a modified instance, by UID and the
+/// original start (`RECURRENCE-ID`) as unix timestamp
+fn overridden_occurrences(calendar: &IcalCalendar, zones: &TimeZones) -> HashSet<(String, i64)> {
+    calendar.events.iter()
+        .filter_map(|event| {
+            let property = |name: &str| event.properties.iter().find(|property| property.name == name);
+            let uid = property("UID")?.value.clone()?;
+            let (local, zone) = zones.parse_property(property("RECURRENCE-ID")?)?;
+            Some((uid, zone.to_utc(local).and_utc().timestamp()))
+        })
+        .collect()
+}
+
+/// Time range of entries, as unix timestamps in seconds. The end is exclusive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl TimeRange {
+    /// The configured window around `now`
+    pub fn around(window: &IcsWindow, now: i64) -> Self {
+        const DAY: i64 = 24 * 60 * 60;
+        Self { start: now - i64::from(window.past_days) * DAY, end: now + i64::from(window.future_days) * DAY }
+    }
+
+    /// Keeps the entries that start within the range
+    fn retain(self, mut entries: Vec<ICSEntry>) -> Vec<ICSEntry> {
+        entries.retain(|entry| (self.start..self.end).contains(&entry.start));
+        entries
+    }
+}
+
+/// Details of a conversion, reported by the sync status
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ConvertStats {
+    /// Events in the calendars, including modified instances of recurring events
+    pub events: usize,
+    /// Recurring events with a rule that can not be expanded, as `<title>: <rule>`
+    pub recurrence_failures: Vec<String>,
+    /// Cancelled events, which are skipped
+    #[serde(default)]
+    pub cancelled: usize,
+    /// Unix timestamps in seconds of the converted time range
+    pub window_start: i64,
+    pub window_end: i64,
+}
+
+/// An event as read from its properties, with `start` and `duration` of its first occurrence
+struct Event {
+    entry: ICSEntry,
+    /// Wall clock time of the start in `zone`
+    local_start: NaiveDateTime,
+    zone: Zone,
+    rrule: Option<String>,
+    rdates: Vec<(NaiveDateTime, Zone)>,
+    exdates: Vec<(NaiveDateTime, Zone)>,
+    /// A modified instance of a recurring event
+    recurrence_id: bool,
+}
+
+impl Event {
+    /// Reads the properties of an event. Returns `None` for events without start.
+    fn read(event: &IcalEvent, zones: &TimeZones) -> Option<Self> {
+        let mut start: Option<(NaiveDateTime, Zone)> = None;
+        let mut end: Option<i64> = None;
+        let mut duration: Option<i64> = None;
+        let mut date_only = false;
+        let mut rrule = None;
+        let mut rdates = Vec::new();
+        let mut exdates = Vec::new();
+        let mut recurrence_id = false;
+        let mut entry = ICSEntry::default();
+        for prop in &event.properties {
+            match &prop.name[..] {
+                "DESCRIPTION" => { entry.desc = prop.value.clone().unwrap_or_default(); }
+                "UID" => { entry.uid = prop.value.clone().unwrap_or_default(); }
+                "SUMMARY" => { entry.title = prop.value.clone().unwrap_or_default(); }
+                "DTSTART" => {
+                    start = zones.parse_property(prop);
+                    date_only = prop.value.as_deref().is_some_and(|value| !value.contains('T'));
+                }
+                "DTEND" => { end = zones.parse_property(prop).map(|(local, zone)| zone.to_utc(local).and_utc().timestamp()); }
+                "DURATION" => { duration = prop.value.as_deref().and_then(parse_duration); }
+                "X-MICROSOFT-CDO-BUSYSTATUS" => {
+                    let v = prop.value.clone().unwrap_or_default();
+                    entry.confirmed = v == "BUSY";
+                    entry.oof = v == "OOF";
+                    entry.busy_status = v;
+                }
+                "LOCATION" => { entry.location = prop.value.clone().unwrap_or_default(); }
+                "ORGANIZER" => { entry.organizer = organizer(prop); }
+                "ATTENDEE" => { entry.attendees += 1; }
+                "STATUS" => { entry.status = prop.value.clone().unwrap_or_default().to_uppercase(); }
+                "TRANSP" => { entry.transp = prop.value.clone().unwrap_or_default().to_uppercase(); }
+                // Outlook and Google, and CONFERENCE of RFC 7986
+                "X-MICROSOFT-SKYPETEAMSMEETINGURL" | "X-GOOGLE-CONFERENCE" | "CONFERENCE" if entry.online_meeting_url.is_empty() => {
+                    entry.online_meeting_url = prop.value.clone().unwrap_or_default();
+                }
+                "CATEGORIES" => {
+                    entry.categories.extend(prop.value.iter().flat_map(|value| value.split(',')).map(|category| category.trim().to_string()).filter(|category| !category.is_empty()));
+                }
+                "RRULE" => { rrule.clone_from(&prop.value); }
+                "RDATE" => { rdates.extend(zones.parse_property_list(prop)); }
+                "EXDATE" => { exdates.extend(zones.parse_property_list(prop)); }
+                "RECURRENCE-ID" => { recurrence_id = true; }
+                _ => {}
+            }
+        }
+        let Some((local_start, zone)) = start else {
+            tracing::warn!("Skipping entry without start {}", entry.title);
+            return None;
+        };
+        entry.start = zone.to_utc(local_start).and_utc().timestamp();
+        entry.all_day = date_only;
+        // Without DTEND and DURATION, an event lasts one day if it starts on a date, and no time otherwise
+        entry.duration = match (end, duration) {
+            (Some(end), _) => end - entry.start,
+            (None, Some(duration)) => duration,
+            (None, None) if date_only => 24 * 60 * 60,
+            (None, None) => 0,
+        };
+
+        // Outlook puts the join link below the separator of the description
+        if entry.online_meeting_url.is_empty() {
+            entry.online_meeting_url = meeting_url(&entry.desc).unwrap_or_default();
+        }
+        if let Some(index) = entry.desc.find("________________________________________________________________________________") {
+            entry.desc.truncate(index);
+        }
+
+        Some(Self { entry, local_start, zone, rrule, rdates, exdates, recurrence_id })
+    }
+
+    /// Recurring rules. A modified instance (RECURRENCE-ID) is a single event, even if it repeats the rule.
+    fn is_recurring(&self) -> bool {
+        !self.recurrence_id && (self.rrule.is_some() || !self.rdates.is_empty())
+    }
+
+    /// The occurrences of a recurring event that reach into `range`, without those in `overridden`.
+    /// Fails if the `RRULE` can not be parsed.
+    fn expand(&self, range: TimeRange, overridden: &HashSet<(String, i64)>) -> Result<Vec<ICSEntry>, RRuleError> {
+        let zone = &self.zone;
+        // Expanded on the wall clock time, so that occurrences keep their local time across DST changes
+        let wall_clock = |(local, other): &(NaiveDateTime, Zone)| Tz::UTC.from_utc_datetime(&zone.to_local(other.to_utc(*local)));
+        let date_time = Tz::UTC.from_utc_datetime(&self.local_start);
+
+        let mut rdates: Vec<_> = self.rdates.iter().map(wall_clock).collect();
+        if self.rrule.is_none() {
+            // Without RRULE, the start is only part of the set as RDATE
+            rdates.push(date_time);
+        }
+        rdates.sort();
+        let mut rrule_set = RRuleSet::new(date_time)
+            .set_rdates(rdates)
+            .set_exdates(self.exdates.iter().map(wall_clock).collect());
+        if let Some(rrule) = &self.rrule {
+            rrule_set = rrule_set.rrule(RRule::from_str(&localize_until(rrule, zone))?.validate(date_time)?);
+        }
+
+        tracing::info!("Recurring entry {} - {}", self.rrule.as_deref().unwrap_or("RDATE"), self.entry.title);
+        let mut occurrences = Vec::new();
+        for i in &rrule_set {
+            let local = i.naive_utc();
+            let i = Utc.from_utc_datetime(&zone.to_utc(local));
+            if i.timestamp() >= range.end { break; }
+            // Multi-day occurrences that started before the range may still reach into it
+            if i.timestamp() + self.entry.duration < range.start { continue; }
+            // Replaced by a modified instance, which is converted on its own
+            if overridden.contains(&(self.entry.uid.clone(), i.timestamp())) { continue; }
+            tracing::info!("Recurring entry {} ({})", i, i.timestamp());
+            let occurrence = ICSEntry { start: i.timestamp(), ..self.entry.clone() };
+            occurrences.extend(range.retain(split_days(&occurrence, local, zone)));
+        }
+        Ok(occurrences)
+    }
+}
+
+/// Converts the events that start within `range`, and the days of multi-day events that do.
+/// Recurring events are expanded for that range only.
+pub fn convert(calendars: &[IcalCalendar], range: TimeRange) -> (Vec<ICSEntry>, ConvertStats) {
+    let mut output = Vec::<ICSEntry>::new();
+    let mut entries_map = HashSet::<(String, i64)>::new();
+
+    let mut stats = ConvertStats {
+        events: calendars.iter().map(|calendar| calendar.events.len()).sum(),
+        window_start: range.start,
+        window_end: range.end,
+        ..Default::default()
+    };
+
+    for calendar in calendars {
+        let zones = TimeZones::new(calendar);
+        let overridden = overridden_occurrences(calendar, &zones);
+
+        for event in calendar.events.iter().filter_map(|event| Event::read(event, &zones)) {
+            let entry = &event.entry;
+            if entry.status == "CANCELLED" {
+                // A cancelled modified instance still replaces its occurrence
+                stats.cancelled += 1;
+                continue;
+            }
+
+            if event.is_recurring() {
+                if let Ok(occurrences) = event.expand(range, &overridden) {
+                    push_unique(&mut output, &mut entries_map, occurrences);
+                } else {
+                    let rrule = event.rrule.as_deref().unwrap_or_default();
+                    tracing::warn!("Failed to parse recurring entry {} - {}", rrule, entry.title);
+                    stats.recurrence_failures.push(format!("{}: {rrule}", entry.title));
+                }
+            } else if entry.start + entry.duration >= range.start && entry.start < range.end {
+                tracing::info!("Normal entry {}", entry.start);
+                push_unique(&mut output, &mut entries_map, range.retain(split_days(entry, event.local_start, &event.zone)));
+            }
+        }
+    }
+
+    (output, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The default window at `now`
+    fn window(now: &str) -> TimeRange {
+        let now = Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(now, "%Y%m%dT%H%M%S").unwrap());
+        TimeRange::around(&IcsWindow::default(), now.timestamp())
+    }
+
+    fn convert_fixture(ics: &str, range: TimeRange) -> Vec<(String, i64, i64)> {
+        convert(&parse(ics), range).0
+            .into_iter()
+            .map(|entry| (entry.uid, entry.start, entry.duration))
+            .collect()
+    }
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, min: u32) -> i64 {
+        Utc.with_ymd_and_hms(year, month, day, hour, min, 0).unwrap().timestamp()
+    }
+
+    #[test]
+    fn outlook_windows_time_zone() {
+        let entries = convert_fixture(include_str!("../../tests/fixtures/outlook_dst.ics"), window("20230410T000000"));
+        assert_eq!(entries, [
+            ("winter".to_string(), utc(2023, 3, 24, 8, 0), 3600),
+            ("summer".to_string(), utc(2023, 3, 28, 7, 0), 3600),
+            ("weekly".to_string(), utc(2023, 3, 20, 8, 30), 1800),
+            ("weekly".to_string(), utc(2023, 3, 27, 7, 30), 1800),
+            // UNTIL is the UTC start of the last occurrence
+            ("weekly".to_string(), utc(2023, 4, 3, 7, 30), 1800),
+            ("utc".to_string(), utc(2023, 3, 29, 9, 0), 3600),
+        ]);
+    }
+
+    #[test]
+    fn custom_vtimezone() {
+        let year = TimeRange { start: utc(2023, 1, 1, 0, 0), end: utc(2024, 1, 1, 0, 0) };
+        let entries = convert_fixture(include_str!("../../tests/fixtures/custom_timezone.ics"), year);
+        let starts = entries.into_iter().map(|(uid, start, _)| (uid, start)).collect::<Vec<_>>();
+        assert_eq!(starts, [
+            ("est".to_string(), utc(2023, 3, 10, 14, 0)),
+            ("edt".to_string(), utc(2023, 3, 13, 13, 0)),
+            ("before-fall-back".to_string(), utc(2023, 11, 3, 13, 0)),
+            ("after-fall-back".to_string(), utc(2023, 11, 6, 14, 0)),
+            // Floating time in the X-WR-TIMEZONE of the calendar
+            ("floating".to_string(), utc(2023, 10, 2, 8, 0)),
+        ]);
+    }
+
+    #[test]
+    fn recurring_exceptions() {
+        let entries = convert_fixture(include_str!("../../tests/fixtures/recurring_overrides.ics"), window("20230610T000000"));
+        let starts = entries.into_iter().map(|(uid, start, _)| (uid, start)).collect::<Vec<_>>();
+        assert_eq!(starts, [
+            // 05-08, 05-29 and 06-05 are excluded, 05-15 is moved
+            ("standup".to_string(), utc(2023, 5, 1, 7, 0)),
+            ("standup".to_string(), utc(2023, 5, 10, 12, 0)),
+            ("standup".to_string(), utc(2023, 5, 22, 7, 0)),
+            ("standup".to_string(), utc(2023, 5, 16, 8, 0)),
+            ("workshop".to_string(), utc(2023, 5, 2, 11, 0)),
+            ("workshop".to_string(), utc(2023, 5, 4, 11, 0)),
+        ]);
+
+        // Any range can be expanded, independent of now
+        let range = TimeRange { start: utc(2023, 5, 15, 0, 0), end: utc(2023, 5, 23, 0, 0) };
+        let entries = convert_fixture(include_str!("../../tests/fixtures/recurring_overrides.ics"), range);
+        let starts = entries.into_iter().map(|(uid, start, _)| (uid, start)).collect::<Vec<_>>();
+        assert_eq!(starts, [
+            ("standup".to_string(), utc(2023, 5, 22, 7, 0)),
+            ("standup".to_string(), utc(2023, 5, 16, 8, 0)),
+        ]);
+    }
+
+    #[test]
+    fn durations_and_duplicates() {
+        let entries = convert_fixture(include_str!("../../tests/fixtures/durations.ics"), window("20230510T000000"));
+        assert_eq!(entries, [
+            ("meeting-a".to_string(), utc(2023, 5, 2, 9, 0), 3600),
+            // Same start, but a different UID
+            ("meeting-b".to_string(), utc(2023, 5, 2, 9, 0), 5400),
+            ("no-end".to_string(), utc(2023, 5, 2, 12, 0), 0),
+            ("all-day".to_string(), utc(2023, 5, 3, 0, 0), 0),
+        ]);
+    }
+
+    #[test]
+    fn event_fields() {
+        let range = TimeRange { start: utc(2023, 5, 1, 0, 0), end: utc(2023, 5, 3, 0, 0) };
+        let (entries, stats) = convert(&parse(include_str!("../../tests/fixtures/fields.ics")), range);
+        let fields: Vec<_> = entries.iter()
+            .map(|entry| (entry.uid.as_str(), entry.location.as_str(), entry.organizer.as_str(), entry.categories.clone(), entry.busy_status.as_str()))
+            .collect();
+        assert_eq!(fields, [
+            ("review", "Room 4.01", "Jane Doe <jane@example.com>", vec!["Project Falco".to_string(), "Review".to_string(), "Internal".to_string()], "TENTATIVE"),
+            ("plain", "", "john@example.com", vec![], ""),
+            ("sync", "", "", vec![], ""),
+        ]);
+
+        let fields: Vec<_> = entries.iter()
+            .map(|entry| (entry.uid.as_str(), entry.attendees, entry.status.as_str(), entry.transp.as_str(), entry.online_meeting_url.as_str()))
+            .collect();
+        assert_eq!(fields, [
+            ("review", 3, "CONFIRMED", "OPAQUE", "https://teams.microsoft.com/l/meetup-join/19%3ameeting_review%40thread.v2/0"),
+            ("plain", 0, "", "TRANSPARENT", ""),
+            // Link from the description, which is cut at the separator
+            ("sync", 0, "TENTATIVE", "", "https://teams.microsoft.com/l/meetup-join/19%3ameeting_sync%40thread.v2/0"),
+        ]);
+        assert_eq!(entries[2].desc, "Agenda in the wiki.\\n\\n");
+        assert_eq!(stats.cancelled, 1);
+    }
+
+    #[test]
+    fn duration_values() {
+        assert_eq!(parse_duration("PT1H30M"), Some(5400));
+        assert_eq!(parse_duration("P1DT2H"), Some(93600));
+        assert_eq!(parse_duration("P2W"), Some(1_209_600));
+        assert_eq!(parse_duration("-PT15M"), Some(-900));
+        assert_eq!(parse_duration("PT15"), None);
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn all_day_and_multi_day_events() {
+        let now = Utc.with_ymd_and_hms(2023, 5, 30, 0, 0, 0).unwrap();
+        let entries: Vec<_> = convert(&parse(include_str!("../../tests/fixtures/all_day.ics")), TimeRange::around(&IcsWindow::default(), now.timestamp())).0
+            .into_iter()
+            .map(|entry| (entry.uid, entry.start, entry.duration, entry.all_day, entry.oof))
+            .collect();
+        let day = |uid: &str, day: u32, month: u32, oof| (uid.to_string(), utc(2023, month, day, 0, 0), 0, true, oof);
+        assert_eq!(entries, [
+            day("dst-weekend", 25, 3, false),
+            day("dst-weekend", 26, 3, false),
+            day("home-office", 5, 5, false),
+            day("home-office", 12, 5, false),
+            // Split at midnight in Berlin
+            ("training".to_string(), utc(2023, 5, 15, 7, 0), 15 * 60 * 60, false, false),
+            ("training".to_string(), utc(2023, 5, 15, 22, 0), 17 * 60 * 60, false, false),
+            day("holiday", 18, 5, false),
+            day("vacation", 22, 5, true),
+            day("vacation", 23, 5, true),
+            day("vacation", 24, 5, true),
+        ]);
+    }
+
+    #[test]
+    fn days_in_the_zone_of_the_user() {
+        let entry = |start: i64, all_day: bool| ICSEntry { start, all_day, ..ICSEntry::default() };
+        let date = NaiveDate::from_ymd_opt(2023, 11, 14);
+        let berlin = DayFilter { zone: chrono_tz::Europe::Berlin, first: date, last: date, ..DayFilter::default() };
+        assert_eq!(berlin.start_of(date.unwrap()), utc(2023, 11, 13, 23, 0));
+
+        // 00:30 and 23:30 in Berlin, the first one still on the day before in UTC
+        assert!(berlin.keeps(&entry(utc(2023, 11, 13, 23, 30), false)));
+        assert!(berlin.keeps(&entry(utc(2023, 11, 14, 22, 30), false)));
+        assert!(!berlin.keeps(&entry(utc(2023, 11, 14, 23, 30), false)));
+        assert!(!DayFilter { zone: chrono_tz::UTC, ..berlin }.keeps(&entry(utc(2023, 11, 13, 23, 30), false)));
+
+        // All-day entries keep their date, also west of UTC
+        let new_york = DayFilter { zone: chrono_tz::America::New_York, month: Some(11), day: Some(14), ..DayFilter::default() };
+        assert!(new_york.keeps(&entry(utc(2023, 11, 14, 0, 0), true)));
+        assert!(!new_york.keeps(&entry(utc(2023, 11, 14, 0, 0), false)));
+    }
+}
@@ -0,0 +1,85 @@
+//! Background task that keeps the caches of all calendar sources up to date, so that requests answer from the cache
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::time::MissedTickBehavior;
+
+use crate::settings::CalendarSource;
+use crate::storage::{unix_secs, Storage};
+
+use super::fetch::{read_meta, refresh_source, CacheMeta};
+use super::TimeRange;
+
+/// How often sources are checked for being due
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+/// Delay after the first failed fetch, doubled with every further failure
+const RETRY_DELAY: u64 = 60;
+const MAX_RETRY_DELAY: u64 = 6 * 60 * 60;
+
+/// Refreshes every calendar source when its cache TTL has expired. Failed fetches are retried with exponential backoff.
+pub async fn run(storage: Arc<dyn Storage>) {
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        sync_due(&storage, unix_secs(SystemTime::now())).await;
+    }
+}
+
+async fn sync_due(storage: &Arc<dyn Storage>, now: u64) {
+    let settings = match storage.get_settings().await {
+        Ok(Some(settings)) => settings,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::error!("ICS sync: Failed to read settings: {}", err);
+            return;
+        }
+    };
+
+    let range = TimeRange::around(&settings.ics_window, i64::try_from(now).unwrap_or(i64::MAX));
+    for source in settings.calendar_sources() {
+        let meta = read_meta(storage, &source).await;
+        if next_sync(&meta, &source) > now {
+            continue;
+        }
+        match refresh_source(storage, &source, range, now).await {
+            Ok((entries, _)) => tracing::info!("ICS sync {}: Entries {}", source.name, entries.len()),
+            Err(err) => tracing::warn!("ICS sync {} failed (attempt {}), retrying in {}s: {}", source.name, meta.failures + 1, retry_delay(meta.failures + 1), err.1),
+        }
+    }
+}
+
+/// Unix timestamp in seconds when a source is fetched next
+pub(super) fn next_sync(meta: &CacheMeta, source: &CalendarSource) -> u64 {
+    match meta.failures {
+        0 => meta.timestamp + source.cache_ttl(),
+        failures => meta.last_attempt + retry_delay(failures),
+    }
+}
+
+fn retry_delay(failures: u32) -> u64 {
+    RETRY_DELAY.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let source = CalendarSource { cache_ttl: Some(3600), ..Default::default() };
+        let mut meta = CacheMeta { timestamp: 1000, last_attempt: 1000, ..Default::default() };
+        assert_eq!(next_sync(&meta, &source), 4600);
+
+        meta.last_attempt = 5000;
+        let delays: Vec<_> = (1..=4).map(|failures| {
+            meta.failures = failures;
+            next_sync(&meta, &source) - 5000
+        }).collect();
+        assert_eq!(delays, [60, 120, 240, 480]);
+
+        meta.failures = 40;
+        assert_eq!(next_sync(&meta, &source), 5000 + MAX_RETRY_DELAY);
+    }
+}
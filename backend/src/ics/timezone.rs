@@ -0,0 +1,334 @@
+//! Resolves `TZID` parameters of ICS date-time values.
+//!
+//! IANA names ("Europe/Berlin") and Windows names ("W. Europe Standard Time") are looked up in the
+//! IANA database, which also knows about historical rule changes. Other names must be defined by a
+//! `VTIMEZONE` block of the calendar, as Outlook does for customized time zones.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use ical::parser::ical::component::{IcalCalendar, IcalTimeZone};
+use ical::property::Property;
+
+use super::windows_zones::WINDOWS_ZONES;
+
+/// Time zone of a date-time value
+#[derive(Clone, Debug)]
+pub enum Zone {
+    Utc,
+    Iana(chrono_tz::Tz),
+    /// Defined by a `VTIMEZONE` block of the calendar
+    Custom(Arc<VTimeZone>),
+}
+
+impl Zone {
+    /// Converts a wall clock time of this zone into UTC.
+    /// Times that do not exist because of a DST change are interpreted with the offset before the change.
+    pub fn to_utc(&self, local: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Self::Utc => local,
+            Self::Iana(tz) => match tz.from_local_datetime(&local) {
+                LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.naive_utc(),
+                LocalResult::None => tz.from_local_datetime(&(local + Duration::hours(1)))
+                    .earliest()
+                    .map_or(local, |time| time.naive_utc()),
+            },
+            Self::Custom(zone) => local - Duration::seconds(zone.offset_at(local, false).into()),
+        }
+    }
+
+    /// Converts a UTC time into the wall clock time of this zone
+    pub fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Self::Utc => utc,
+            Self::Iana(tz) => tz.from_utc_datetime(&utc).naive_local(),
+            Self::Custom(zone) => utc + Duration::seconds(zone.offset_at(utc, true).into()),
+        }
+    }
+}
+
+/// Day of the month of a yearly `RRULE`, ie `BYDAY=-1SU` (last Sunday) or `BYDAY=2SU` (second Sunday)
+#[derive(Debug)]
+struct YearlyRule {
+    month: u32,
+    weekday: Option<Weekday>,
+    /// 1 is the first matching day of the month, -1 the last one
+    week: Option<i32>,
+    month_days: Vec<u32>,
+    /// UTC
+    until: Option<NaiveDateTime>,
+}
+
+impl YearlyRule {
+    /// Parses the subset of `RRULE` used for time zone transitions. Returns `None` for unsupported rules.
+    fn parse(value: &str) -> Option<Self> {
+        let mut rule = Self { month: 0, weekday: None, week: None, month_days: Vec::new(), until: None };
+        for part in value.split(';') {
+            let (key, value) = part.split_once('=')?;
+            match key {
+                "FREQ" if value == "YEARLY" => {}
+                "INTERVAL" if value == "1" => {}
+                "BYMONTH" => rule.month = value.parse().ok()?,
+                "BYMONTHDAY" => rule.month_days = value.split(',').map(str::parse).collect::<Result<_, _>>().ok()?,
+                "BYDAY" => {
+                    let (week, day) = value.split_at(value.len().checked_sub(2)?);
+                    rule.weekday = Some(parse_weekday(day)?);
+                    rule.week = if week.is_empty() { None } else { Some(week.trim_start_matches('+').parse().ok()?) };
+                }
+                "UNTIL" => rule.until = Some(parse_date_time(value.trim_end_matches('Z'))?),
+                "WKST" => {}
+                _ => return None,
+            }
+        }
+        (1..=12).contains(&rule.month).then_some(rule)
+    }
+
+    fn date(&self, year: i32) -> Option<NaiveDate> {
+        let candidates: Vec<NaiveDate> = (1..=31)
+            .filter(|day| self.month_days.is_empty() || self.month_days.contains(day))
+            .filter_map(|day| NaiveDate::from_ymd_opt(year, self.month, day))
+            .filter(|date| self.weekday.is_none_or(|weekday| date.weekday() == weekday))
+            .collect();
+        match self.week {
+            Some(week) if week > 0 => candidates.get(usize::try_from(week - 1).ok()?).copied(),
+            Some(week) => candidates.len().checked_sub(usize::try_from(-week).ok()?).and_then(|index| candidates.get(index)).copied(),
+            None => candidates.first().copied(),
+        }
+    }
+}
+
+/// A `STANDARD` or `DAYLIGHT` block of a `VTIMEZONE`
+#[derive(Debug)]
+struct Observance {
+    /// Offsets to UTC in seconds
+    offset_from: i32,
+    offset_to: i32,
+    /// First onset, in local time before the transition
+    start: NaiveDateTime,
+    rule: Option<YearlyRule>,
+    rdates: Vec<NaiveDateTime>,
+}
+
+impl Observance {
+    /// The latest onset at or before `time`. With `utc`, `time` and the result are UTC, local time otherwise.
+    fn last_onset(&self, time: NaiveDateTime, utc: bool) -> Option<NaiveDateTime> {
+        let offset = Duration::seconds(self.offset_from.into());
+        let rule_onsets = self.rule.iter().flat_map(|rule| {
+            [time.year() - 1, time.year()].into_iter()
+                .filter_map(|year| rule.date(year).map(|date| date.and_time(self.start.time())))
+                .filter(|onset| *onset >= self.start && rule.until.is_none_or(|until| *onset - offset <= until))
+        });
+
+        std::iter::once(self.start)
+            .chain(self.rdates.iter().copied())
+            .chain(rule_onsets)
+            .map(|onset| if utc { onset - offset } else { onset })
+            .filter(|onset| *onset <= time)
+            .max()
+    }
+}
+
+#[derive(Debug)]
+pub struct VTimeZone {
+    observances: Vec<Observance>,
+}
+
+impl VTimeZone {
+    fn parse(timezone: &IcalTimeZone) -> Self {
+        let observances = timezone.transitions.iter().filter_map(|transition| {
+            let value = |name: &str| transition.properties.iter()
+                .find(|property| property.name == name)
+                .and_then(|property| property.value.as_deref());
+            let rule = value("RRULE").and_then(|rule| {
+                let parsed = YearlyRule::parse(rule);
+                if parsed.is_none() {
+                    tracing::warn!("Unsupported time zone rule {}", rule);
+                }
+                parsed
+            });
+            Some(Observance {
+                offset_from: parse_offset(value("TZOFFSETFROM")?)?,
+                offset_to: parse_offset(value("TZOFFSETTO")?)?,
+                start: parse_date_time(value("DTSTART")?)?,
+                rule,
+                rdates: value("RDATE").map(|rdates| rdates.split(',').filter_map(parse_date_time).collect()).unwrap_or_default(),
+            })
+        }).collect();
+        Self { observances }
+    }
+
+    /// Offset to UTC in seconds at the given UTC (`utc`) or local time
+    fn offset_at(&self, time: NaiveDateTime, utc: bool) -> i32 {
+        let current = self.observances.iter()
+            .filter_map(|observance| observance.last_onset(time, utc).map(|onset| (onset, observance)))
+            .max_by_key(|(onset, _)| *onset);
+
+        match current {
+            // Local times skipped by the transition still use the previous offset
+            Some((onset, observance)) if !utc && observance.offset_to > observance.offset_from
+                && time < onset + Duration::seconds((observance.offset_to - observance.offset_from).into()) => observance.offset_from,
+            Some((_, observance)) => observance.offset_to,
+            None => self.observances.iter().min_by_key(|observance| observance.start).map_or(0, |observance| observance.offset_from),
+        }
+    }
+}
+
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    Some(match value {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// Parses an UTC offset like `+0100`, `-0500` or `+053000` into seconds
+fn parse_offset(value: &str) -> Option<i32> {
+    let (sign, digits) = match value.as_bytes().first()? {
+        b'+' => (1, &value[1..]),
+        b'-' => (-1, &value[1..]),
+        _ => return None,
+    };
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let part = |range: std::ops::Range<usize>| digits.get(range).map_or(0, |part| part.parse::<i32>().unwrap_or_default());
+    Some(sign * (part(0..2) * 3600 + part(2..4) * 60 + part(4..6)))
+}
+
+/// Parses a local date-time like `20230326T020000`
+fn parse_date_time(value: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()
+}
+
+/// The time zones of a calendar
+pub struct TimeZones {
+    custom: HashMap<String, Arc<VTimeZone>>,
+    /// Zone of date-times without `TZID`, from `X-WR-TIMEZONE`. UTC if not set.
+    default: Zone,
+}
+
+impl TimeZones {
+    pub fn new(calendar: &IcalCalendar) -> Self {
+        let custom = calendar.timezones.iter()
+            .filter_map(|timezone| {
+                let tzid = timezone.properties.iter().find(|property| property.name == "TZID")?.value.clone()?;
+                Some((tzid, Arc::new(VTimeZone::parse(timezone))))
+            })
+            .collect();
+        let mut zones = Self { custom, default: Zone::Utc };
+        if let Some(name) = calendar.properties.iter().find(|property| property.name == "X-WR-TIMEZONE").and_then(|property| property.value.as_deref()) {
+            zones.default = zones.resolve(name);
+        }
+        zones
+    }
+
+    /// Looks up a `TZID` in the IANA database, the Windows zone names and the calendar's `VTIMEZONE` blocks
+    pub fn resolve(&self, tzid: &str) -> Zone {
+        let tzid = tzid.trim().trim_matches('"');
+        if let Ok(tz) = chrono_tz::Tz::from_str(tzid) {
+            return Zone::Iana(tz);
+        }
+        if let Some(tz) = WINDOWS_ZONES.iter().find(|(name, _)| *name == tzid).and_then(|(_, iana)| chrono_tz::Tz::from_str(iana).ok()) {
+            return Zone::Iana(tz);
+        }
+        if let Some(zone) = self.custom.get(tzid) {
+            return Zone::Custom(zone.clone());
+        }
+        tracing::warn!("Unknown time zone {}", tzid);
+        self.default.clone()
+    }
+
+    fn property_zone(&self, property: &Property) -> Zone {
+        let tzid = property.params.iter().flatten()
+            .find(|(name, _)| name.eq_ignore_ascii_case("TZID"))
+            .and_then(|(_, values)| values.first());
+        tzid.map_or_else(|| self.default.clone(), |tzid| self.resolve(tzid))
+    }
+
+    fn parse_value(value: &str, zone: &Zone) -> Option<(NaiveDateTime, Zone)> {
+        let value = value.trim();
+        if let Some(utc) = value.strip_suffix('Z') {
+            return Some((parse_date_time(utc)?, Zone::Utc));
+        }
+
+        let local = if value.contains('T') {
+            parse_date_time(value)?
+        } else {
+            NaiveDate::parse_from_str(value, "%Y%m%d").ok()?.and_hms_opt(0, 0, 0)?
+        };
+        Some((local, zone.clone()))
+    }
+
+    /// Parses the value of a `DATE` or `DATE-TIME` property like `DTSTART` into the wall clock time and its zone.
+    /// Dates without time start at midnight.
+    pub fn parse_property(&self, property: &Property) -> Option<(NaiveDateTime, Zone)> {
+        Self::parse_value(property.value.as_deref()?, &self.property_zone(property))
+    }
+
+    /// Parses a comma separated list like `EXDATE` or `RDATE`. Periods (`start/end` or `start/duration`)
+    /// are reduced to their start. Values that can not be parsed are skipped.
+    pub fn parse_property_list(&self, property: &Property) -> Vec<(NaiveDateTime, Zone)> {
+        let zone = self.property_zone(property);
+        property.value.as_deref().unwrap_or_default()
+            .split(',')
+            .filter_map(|value| Self::parse_value(value.split('/').next().unwrap_or_default(), &zone))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        parse_date_time(value).unwrap()
+    }
+
+    #[test]
+    fn windows_zones_are_known() {
+        for (windows, iana) in WINDOWS_ZONES {
+            assert!(chrono_tz::Tz::from_str(iana).is_ok(), "{windows} => {iana}");
+        }
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("+0100"), Some(3600));
+        assert_eq!(parse_offset("-0430"), Some(-16200));
+        assert_eq!(parse_offset("+053000"), Some(19800));
+        assert_eq!(parse_offset("0100"), None);
+    }
+
+    #[test]
+    fn yearly_rules() {
+        let last_sunday = YearlyRule::parse("FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3").unwrap();
+        assert_eq!(last_sunday.date(2023), NaiveDate::from_ymd_opt(2023, 3, 26));
+        assert_eq!(last_sunday.date(2024), NaiveDate::from_ymd_opt(2024, 3, 31));
+        let second_sunday = YearlyRule::parse("FREQ=YEARLY;INTERVAL=1;BYDAY=2SU;BYMONTH=3").unwrap();
+        assert_eq!(second_sunday.date(2023), NaiveDate::from_ymd_opt(2023, 3, 12));
+        let month_days = YearlyRule::parse("FREQ=YEARLY;BYMONTH=4;BYDAY=SU;BYMONTHDAY=1,2,3,4,5,6,7").unwrap();
+        assert_eq!(month_days.date(2006), NaiveDate::from_ymd_opt(2006, 4, 2));
+        assert!(YearlyRule::parse("FREQ=MONTHLY;BYDAY=1SU").is_none());
+    }
+
+    #[test]
+    fn iana_dst_transitions() {
+        let zones = TimeZones::new(&IcalCalendar::new());
+        let berlin = zones.resolve("W. Europe Standard Time");
+        assert!(matches!(berlin, Zone::Iana(chrono_tz::Tz::Europe__Berlin)));
+
+        assert_eq!(berlin.to_utc(time("20230326T015900")), time("20230326T005900"));
+        // 02:30 does not exist on that day
+        assert_eq!(berlin.to_utc(time("20230326T023000")), time("20230326T013000"));
+        assert_eq!(berlin.to_utc(time("20230326T030000")), time("20230326T010000"));
+        assert_eq!(berlin.to_utc(time("20231029T040000")), time("20231029T030000"));
+        assert_eq!(berlin.to_local(time("20231029T010000")), time("20231029T020000"));
+    }
+}
@@ -9,9 +9,15 @@ use std::path::{PathBuf};
 use tracing::log::warn;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod archive;
+mod ics;
+mod migrate;
 pub mod middlewares;
 pub mod routes;
+mod settings;
+mod storage;
 mod store;
+mod timesheet;
 
 // SETUP Constants
 const WWW_DIR: &str = "./build";
@@ -19,6 +25,8 @@ const SERVER_PORT: &str = "8099";
 const SERVER_HOST: &str = "0.0.0.0";
 const API_FIXED_SECRET: &str = "123456789";
 const TIME_SHEET_DIR: &str = "./data";
+const STORAGE: &str = "file";
+const DATABASE_FILE: &str = "timesheets.sqlite";
 
 #[tokio::main]
 async fn main() {
@@ -35,6 +43,16 @@ async fn main() {
 
     let timesheet_dir = PathBuf::from(timesheet_dir);
 
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        if command != "migrate" {
+            eprintln!("Unknown command \"{}\"\n\n{}", command, migrate::USAGE);
+            std::process::exit(2);
+        }
+        migrate(args, storage_from_env(timesheet_dir)).await;
+        return;
+    }
+
     tokio::fs::create_dir_all(&timesheet_dir)
         .await
         .expect("failed to create `timesheet_dir` directory");
@@ -53,9 +71,21 @@ async fn main() {
         }
     }
 
-    let shared_state = Arc::new(store::Store::new(secret, timesheet_dir));
+    let storage_config = storage_from_env(timesheet_dir);
+    tracing::info!("Using {:?} storage (STORAGE)", storage_config.kind);
+    let storage = storage::open(&storage_config)
+        .await
+        .expect("failed to open storage");
+
+    if let Ok(dir) = std::env::var("ICS_FILE_DIR") {
+        let dir = std::fs::canonicalize(&dir).unwrap_or_else(|_| PathBuf::from(dir));
+        tracing::info!("Reading calendar files from {} (ICS_FILE_DIR)", dir.display());
+        ics::allow_files_in(dir);
+    }
+
+    tokio::spawn(ics::sync::run(storage.clone()));
 
-    // crate::routes::api::fetch_ics(State(shared_state));
+    let shared_state = Arc::new(store::Store::new(secret, storage));
 
     // combine the front and backend into server
     let app = Router::new()
@@ -69,6 +99,33 @@ async fn main() {
         .unwrap();
 }
 
+/// `timesheet-backend migrate`: copies all data into another storage backend and prints a verification report
+async fn migrate(args: impl Iterator<Item = String>, configured: storage::StorageConfig) {
+    let configs = migrate::MigrateArgs::parse(args)
+        .and_then(|args| Ok((args.configs(&configured)?, args.overwrite)));
+    let ((source, target), overwrite) = match configs {
+        Ok(configs) => configs,
+        Err(err) => {
+            eprintln!("{err}\n\n{}", migrate::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    println!("Migrating {:?} storage into {:?} storage", source.kind, target.kind);
+    match migrate::run(&source, &target, overwrite).await {
+        Ok(report) => {
+            println!("{report}");
+            if !report.is_ok() {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Migration failed: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
 /// Tokio signal handler that will wait for a user to press CTRL+C.
 /// We use this in our `Server` method `with_graceful_shutdown`.
 async fn shutdown_signal() {
@@ -89,3 +146,28 @@ fn from_env() -> (String, String, String, String, String) {
         std::env::var("WWW_DIR").ok().unwrap_or_else(|| { WWW_DIR.to_string() }),
     )
 }
+
+// Storage backend from Environment: STORAGE is either "file" or "sqlite".
+// The SQLite database is DATABASE_PATH, by default timesheets.sqlite in the data directory.
+fn storage_from_env(timesheet_dir: PathBuf) -> storage::StorageConfig {
+    storage::StorageConfig {
+        kind: std::env::var("STORAGE").ok().unwrap_or_else(|| STORAGE.to_string())
+            .parse()
+            .expect("Can not parse STORAGE"),
+        database_path: std::env::var("DATABASE_PATH").ok().map_or_else(|| timesheet_dir.join(DATABASE_FILE), PathBuf::from),
+        data_dir: timesheet_dir,
+        retention: retention_from_env(),
+    }
+}
+
+// Revision retention policy from Environment:
+// REVISIONS_MAX revisions per month, revisions older than REVISIONS_MAX_AGE_DAYS are removed
+fn retention_from_env() -> storage::RetentionPolicy {
+    let default = storage::RetentionPolicy::default();
+    storage::RetentionPolicy {
+        max_revisions: std::env::var("REVISIONS_MAX").ok().and_then(|v| v.parse().ok()).unwrap_or(default.max_revisions),
+        max_age: std::env::var("REVISIONS_MAX_AGE_DAYS").ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60)),
+    }
+}
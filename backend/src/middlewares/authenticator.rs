@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use axum::{
     extract::State,
-    http::{self, Request, StatusCode},
+    http::{self, header::HeaderName, Request, StatusCode},
     middleware::Next,
     response::Response,
     Json,
@@ -44,6 +44,31 @@ pub async fn auth<B: Send + Sync>(
     }
 }
 
+/// Sent with a 401 of the timesheets feed, so that calendar apps ask for credentials
+const BASIC_CHALLENGE: &str = "Basic realm=\"timesheets\"";
+
+/// Like [`auth`], but also accepts HTTP basic auth with the token as password, for the timesheets feed.
+/// Calendar apps can not send a bearer token, hence a 401 asks for basic auth with `WWW-Authenticate`.
+#[allow(clippy::missing_errors_doc)]
+pub async fn feed_auth<B: Send + Sync>(
+    State(store): State<Arc<Store>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, [(HeaderName, &'static str); 1], Json<JsonError>)> {
+    let authorized = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|header| store.api_token_check(header) || store.basic_auth_check(header));
+
+    if authorized {
+        Ok(next.run(req).await)
+    } else {
+        tracing::debug!("Authorization of the timesheets feed missing or NOT matching");
+        Err((StatusCode::UNAUTHORIZED, [(http::header::WWW_AUTHENTICATE, BASIC_CHALLENGE)], Json(JsonError::unauthorized())))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct JsonError {
     error: String,
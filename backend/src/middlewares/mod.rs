@@ -1,3 +1,3 @@
 mod authenticator;
 
-pub use authenticator::auth;
+pub use authenticator::{auth, feed_auth};
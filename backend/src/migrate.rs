@@ -0,0 +1,369 @@
+//! `timesheet-backend migrate` copies settings, timesheets with their revisions and the ICS caches
+//! from one storage backend into another and verifies the result.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::ics;
+use crate::settings::Settings;
+use crate::storage::{self, StorageConfig, StorageKind};
+use crate::timesheet::{MonthKey, OneMonth};
+
+pub const USAGE: &str = "Usage: timesheet-backend migrate --to <file|sqlite> [--from <file|sqlite>] [--target <path>] [--overwrite]
+
+Copies all data of the storage backend selected by STORAGE (or --from) into the other backend.
+The target is the data directory (file) or database file (sqlite) configured by TIME_SHEET_DIR and
+DATABASE_PATH, unless --target is given. A target that already contains data is only written with --overwrite,
+which also removes the timesheets that only exist in the target. Their history is kept as deleted revision.
+Timesheets are copied with the revisions of their current version history. Deleted revisions, and timesheets
+that have been deleted in the source, are not copied.";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MigrateArgs {
+    pub from: Option<StorageKind>,
+    pub to: StorageKind,
+    /// Data directory or database file of the target, overriding the configured one
+    pub target: Option<PathBuf>,
+    pub overwrite: bool,
+}
+
+impl MigrateArgs {
+    /// Parses the arguments following `migrate`
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let (mut from, mut to, mut target, mut overwrite) = (None, None, None, false);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for {arg}"));
+            match arg.as_str() {
+                "--from" => from = Some(value()?.parse()?),
+                "--to" => to = Some(value()?.parse()?),
+                "--target" => target = Some(PathBuf::from(value()?)),
+                "--overwrite" => overwrite = true,
+                _ => return Err(format!("Unknown argument \"{arg}\"")),
+            }
+        }
+        let to = to.ok_or("Missing --to")?;
+        Ok(Self { from, to, target, overwrite })
+    }
+
+    /// Source and target storage, based on the configured storage
+    pub fn configs(&self, configured: &StorageConfig) -> Result<(StorageConfig, StorageConfig), String> {
+        let source = StorageConfig { kind: self.from.unwrap_or(configured.kind), ..configured.clone() };
+        let mut target = StorageConfig { kind: self.to, ..configured.clone() };
+        match (&self.target, target.kind) {
+            (Some(path), StorageKind::File) => target.data_dir.clone_from(path),
+            (Some(path), StorageKind::Sqlite) => target.database_path.clone_from(path),
+            (None, _) => {}
+        }
+
+        let same = match source.kind {
+            StorageKind::File => target.kind == StorageKind::File && source.data_dir == target.data_dir,
+            StorageKind::Sqlite => target.kind == StorageKind::Sqlite && source.database_path == target.database_path,
+        };
+        if same {
+            return Err("Source and target storage are the same".into());
+        }
+        Ok((source, target))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Copied, but the data does not pass validation. Timesheets are copied unchanged.
+    Warning,
+    Mismatch,
+}
+
+/// Result of a migration, one line per copied item
+#[derive(Debug, Default)]
+pub struct Report {
+    pub items: Vec<(Status, String)>,
+}
+
+impl Report {
+    fn push(&mut self, status: Status, message: impl Into<String>) {
+        self.items.push((status, message.into()));
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.items.iter().filter(|(s, _)| *s == status).count()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.count(Status::Mismatch) == 0
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (status, message) in &self.items {
+            let status = match status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Mismatch => "MISMATCH",
+            };
+            writeln!(f, "{status:>8}  {message}")?;
+        }
+        write!(f, "{} ok, {} warnings, {} mismatches", self.count(Status::Ok), self.count(Status::Warning), self.count(Status::Mismatch))
+    }
+}
+
+/// A timesheet as read from the source, with its stored revisions (oldest first)
+struct MonthData {
+    date: MonthKey,
+    current: OneMonth,
+    revisions: Vec<OneMonth>,
+}
+
+/// Everything that is migrated
+struct Snapshot {
+    settings: Option<Settings>,
+    months: Vec<MonthData>,
+    caches: Vec<(String, Vec<u8>)>,
+}
+
+fn io_error(context: &str) -> impl Fn(std::io::Error) -> String + '_ {
+    move |err| format!("{context}: {err}")
+}
+
+/// Reads and checks all data of the source. Fails if anything can not be read,
+/// so that nothing is written to the target in that case.
+async fn read_source(config: &StorageConfig, report: &mut Report) -> Result<Snapshot, String> {
+    let source = storage::open(config).await.map_err(io_error("Failed to open source storage"))?;
+
+    let settings = source.get_settings().await.map_err(io_error("Failed to read settings"))?;
+
+    let mut dates: Vec<MonthKey> = source.list_months().await
+        .map_err(io_error("Failed to list timesheets"))?
+        .into_iter()
+        .map(|summary| summary.date)
+        .collect();
+    dates.sort();
+
+    // The file storage skips unreadable timesheets when listing, they must not get lost silently
+    if config.kind == StorageKind::File {
+        let mut unreadable = Vec::new();
+        let mut dir = tokio::fs::read_dir(&config.data_dir).await.map_err(io_error("Failed to read data directory"))?;
+        while let Some(file) = dir.next_entry().await.map_err(io_error("Failed to read data directory"))? {
+            let name = file.file_name().to_string_lossy().into_owned();
+            let Some(date) = name.strip_suffix(".timesheet") else {
+                continue;
+            };
+            if !date.parse::<MonthKey>().is_ok_and(|date| dates.contains(&date)) {
+                unreadable.push(name);
+            }
+        }
+        if !unreadable.is_empty() {
+            unreadable.sort();
+            return Err(format!("Unreadable timesheets, fix or remove them first: {}", unreadable.join(", ")));
+        }
+    }
+
+    let mut months = Vec::new();
+    for date in dates {
+        let context = format!("Failed to read timesheet {date}");
+        let current = source.get_month(&date).await
+            .map_err(io_error(&context))?
+            .ok_or_else(|| format!("Timesheet {date} disappeared while reading"))?;
+        if (current.year, current.month) != (date.year, date.month) {
+            return Err(format!("Timesheet {date} contains {}-{:02}", current.year, current.month));
+        }
+        for error in current.validate(None, None) {
+            report.push(Status::Warning, format!("timesheet {date}: {} {}", error.field, error.message));
+        }
+
+        let mut revisions = Vec::new();
+        for info in source.list_revisions(&date).await.map_err(io_error(&context))? {
+            if info.deleted {
+                continue;
+            }
+            if let Some(revision) = source.get_revision(&date, info.revision).await.map_err(io_error(&context))? {
+                revisions.push(revision);
+            }
+        }
+        months.push(MonthData { date, current, revisions });
+    }
+
+    // Cache entries written by `fetch_ics`, one set per calendar source
+    let cache_names = settings.iter().flat_map(Settings::calendar_sources).flat_map(|source| ics::cache_names(&source));
+    let mut caches = Vec::new();
+    for name in cache_names {
+        if let Some(data) = source.get_cache(&name).await.map_err(io_error("Failed to read ICS cache"))? {
+            caches.push((name, data));
+        }
+    }
+
+    Ok(Snapshot { settings, months, caches })
+}
+
+async fn write_target(config: &StorageConfig, snapshot: &Snapshot, overwrite: bool, report: &mut Report) -> Result<(), String> {
+    let target = storage::open(config).await.map_err(io_error("Failed to open target storage"))?;
+
+    let existing = target.list_months().await.map_err(io_error("Failed to read target"))?;
+    if overwrite {
+        // Replacing the target must not leave timesheets behind that the source does not have
+        for summary in existing {
+            if !snapshot.months.iter().any(|month| month.date == summary.date) {
+                let context = format!("Failed to remove timesheet {}", summary.date);
+                target.delete_month(&summary.date).await.map_err(io_error(&context))?;
+                report.push(Status::Ok, format!("timesheet {}: removed, not in source", summary.date));
+            }
+        }
+    } else {
+        let has_settings = target.get_settings().await.map_err(io_error("Failed to read target"))?.is_some();
+        if has_settings || !existing.is_empty() {
+            return Err("The target storage already contains data. Use --overwrite to replace it.".into());
+        }
+    }
+
+    if let Some(settings) = &snapshot.settings {
+        target.set_settings(settings).await.map_err(io_error("Failed to write settings"))?;
+    }
+
+    for month in &snapshot.months {
+        let context = format!("Failed to write timesheet {}", month.date);
+        // Replaying the revisions keeps the history, the last write is the current version
+        for revision in &month.revisions {
+            target.put_month(&month.date, revision).await.map_err(io_error(&context))?;
+        }
+        if month.revisions.last() != Some(&month.current) {
+            target.put_month(&month.date, &month.current).await.map_err(io_error(&context))?;
+        }
+    }
+
+    for (name, data) in &snapshot.caches {
+        target.set_cache(name, data).await.map_err(io_error("Failed to write ICS cache"))?;
+    }
+    Ok(())
+}
+
+/// Reopens the target and compares it against what was read from the source
+async fn verify(config: &StorageConfig, snapshot: &Snapshot, report: &mut Report) -> Result<(), String> {
+    let target = storage::open(config).await.map_err(io_error("Failed to open target storage"))?;
+
+    let settings = target.get_settings().await.map_err(io_error("Failed to read settings"))?;
+    match (&snapshot.settings, settings == snapshot.settings) {
+        (None, true) => report.push(Status::Ok, "settings: none stored"),
+        (Some(_), true) => report.push(Status::Ok, "settings"),
+        (_, false) => report.push(Status::Mismatch, "settings"),
+    }
+
+    for month in &snapshot.months {
+        let context = format!("Failed to read timesheet {}", month.date);
+        let stored = target.get_month(&month.date).await.map_err(io_error(&context))?;
+        let revisions = target.list_revisions(&month.date).await.map_err(io_error(&context))?.len();
+        let message = format!("timesheet {}: {revisions} revisions", month.date);
+        if stored.as_ref() == Some(&month.current) {
+            report.push(Status::Ok, message);
+        } else {
+            report.push(Status::Mismatch, message);
+        }
+    }
+
+    for (name, data) in &snapshot.caches {
+        let stored = target.get_cache(name).await.map_err(io_error("Failed to read ICS cache"))?;
+        let message = format!("cache {name}: {} bytes", data.len());
+        if stored.as_ref() == Some(data) {
+            report.push(Status::Ok, message);
+        } else {
+            report.push(Status::Mismatch, message);
+        }
+    }
+    Ok(())
+}
+
+/// Copies all data from `source` into `target`. Nothing is written if the source can not be read completely.
+pub async fn run(source: &StorageConfig, target: &StorageConfig, overwrite: bool) -> Result<Report, String> {
+    let mut report = Report::default();
+    let snapshot = read_source(source, &mut report).await?;
+    write_target(target, &snapshot, overwrite, &mut report).await?;
+    verify(target, &snapshot, &mut report).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RetentionPolicy;
+    use crate::timesheet::{days_in_month, OneDay};
+
+    fn config(kind: StorageKind, dir: &std::path::Path) -> StorageConfig {
+        StorageConfig {
+            kind,
+            data_dir: dir.join("data"),
+            database_path: dir.join("timesheets.sqlite"),
+            retention: RetentionPolicy::default(),
+        }
+    }
+
+    fn month(change_id: u64) -> OneMonth {
+        OneMonth {
+            days: vec![OneDay::default(); days_in_month(2022, 12).unwrap() as usize],
+            year: 2022,
+            month: 12,
+            created: 1,
+            change_id,
+        }
+    }
+
+    #[tokio::test]
+    async fn file_to_sqlite_and_back() {
+        let dir = std::env::temp_dir().join(format!("timesheet-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let date: MonthKey = "2022_12".parse().unwrap();
+
+        let file = config(StorageKind::File, &dir);
+        let source = storage::open(&file).await.unwrap();
+        let settings = Settings { name: "Jane".into(), ics_url: "https://outlook/calendar.ics".into(), ..Settings::default() };
+        let [cache_name, ..] = ics::cache_names(&settings.calendar_sources()[0]);
+        source.set_settings(&settings).await.unwrap();
+        source.put_month(&date, &month(1)).await.unwrap();
+        source.put_month(&date, &month(2)).await.unwrap();
+        source.set_cache(&cache_name, b"[]").await.unwrap();
+
+        let sqlite = config(StorageKind::Sqlite, &dir);
+        let report = run(&file, &sqlite, false).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        let target = storage::open(&sqlite).await.unwrap();
+        assert_eq!(target.get_month(&date).await.unwrap(), Some(month(2)));
+        assert_eq!(target.list_revisions(&date).await.unwrap().len(), 2);
+        assert_eq!(target.get_cache(&cache_name).await.unwrap(), Some(b"[]".to_vec()));
+
+        // The target is not empty anymore
+        assert!(run(&file, &sqlite, false).await.is_err());
+
+        // Overwriting removes timesheets that only exist in the target
+        let stray: MonthKey = "2021_5".parse().unwrap();
+        target.put_month(&stray, &OneMonth { year: 2021, month: 5, ..month(1) }).await.unwrap();
+        let report = run(&file, &sqlite, true).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(target.get_month(&stray).await.unwrap(), None);
+        assert_eq!(target.get_month(&date).await.unwrap(), Some(month(2)));
+
+        let back = StorageConfig { data_dir: dir.join("back"), ..file.clone() };
+        let report = run(&sqlite, &back, false).await.unwrap();
+        assert!(report.is_ok(), "{}", report);
+        let back = storage::open(&back).await.unwrap();
+        assert_eq!(back.get_settings().await.unwrap(), source.get_settings().await.unwrap());
+
+        // Unreadable timesheets abort the migration before anything is written
+        std::fs::write(file.data_dir.join("2023_1.timesheet"), "{").unwrap();
+        let empty = StorageConfig { database_path: dir.join("empty.sqlite"), ..sqlite };
+        assert!(run(&file, &empty, false).await.is_err());
+        assert!(storage::open(&empty).await.unwrap().get_settings().await.unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = |list: &[&str]| MigrateArgs::parse(list.iter().map(ToString::to_string));
+        assert_eq!(
+            args(&["--to", "sqlite", "--overwrite"]),
+            Ok(MigrateArgs { from: None, to: StorageKind::Sqlite, target: None, overwrite: true })
+        );
+        assert!(args(&[]).is_err());
+        assert!(args(&["--to"]).is_err());
+        assert!(args(&["--to", "mysql"]).is_err());
+    }
+}
@@ -1,24 +1,20 @@
-use std::{fs, io};
-use std::collections::HashSet;
-use std::str::FromStr;
+use std::io;
 use std::sync::Arc;
-use std::time::{Duration, UNIX_EPOCH};
-use axum::{response::IntoResponse, Json};
-use axum::body::StreamBody;
-use axum::extract::{BodyStream, Path, State};
+use axum::{response::{IntoResponse, Response}, Json};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
 use axum::headers::HeaderMap;
 use axum::http::{header, StatusCode};
-use chrono::TimeZone;
 use serde_json::{json};
 use serde::{Deserialize, Serialize};
-use futures::stream::{TryStreamExt};
-use ical::parser::ical::component::IcalEvent;
-use time::Month;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio_util::io::{ReaderStream, StreamReader};
 
+use crate::archive::{self, ArchiveFormat, ImportMode};
+use crate::ics;
+use crate::settings::{FeedTimes, FilterRule, IcsWindow, Settings};
+use crate::storage::{MonthSummary, RevisionInfo};
 use crate::store::Store;
+use crate::timesheet::{diff_days, DayDiff, FieldError, MonthKey, OneMonth, ValidationErrors};
+use crate::timesheet::merge::{self, MergeConflicts};
 
 #[allow(clippy::unused_async)]
 pub async fn handler() -> impl IntoResponse {
@@ -27,379 +23,715 @@ pub async fn handler() -> impl IntoResponse {
     )
 }
 
-#[derive(Serialize, Deserialize, Default)]
-pub struct Settings {
-    ics_url: String,
-    ics_filter: Vec<String>,
-    projects: Vec<String>,
-    tags: Vec<String>,
-    name: String,
-    company: String,
-    client: String,
-    gitlab_url: String,
-    gitlab_access_token: String,
-    last_updated: Option<u64>,
-}
-
-async fn read_file<T: serde::de::DeserializeOwned>(path: &std::path::Path) -> Result<Json<T>, io::Error> {
-    let mut contents = vec![];
-    let mut file = File::open(path).await?;
-    file.read_to_end(&mut contents).await?;
-    let value: T = serde_json::from_slice(&contents)?;
-    Ok(Json(value))
+/// Reads the settings. Responds with 404 if no settings have been stored yet.
+async fn read_settings(store: &Store) -> Result<Settings, (StatusCode, String)> {
+    store.storage.get_settings().await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "No settings stored".to_string()))
 }
 
 pub async fn get_settings(State(store): State<Arc<Store>>) -> Result<Json<Settings>, (StatusCode, String)> {
     tracing::info!("Get settings");
 
-    let path = store.upload_dir.join("settings.json");
-
-    read_file::<Settings>(&path).await.map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))
+    Ok(Json(read_settings(&store).await?))
 }
 
 fn get_now() -> u64 {
     std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
+/// Checks filters, calendar urls, mapping rules and feed settings. Responds with 422 if anything is invalid.
+fn validate_settings(settings: &Settings) -> Result<(), (StatusCode, String)> {
+    for source in settings.calendar_sources() {
+        ics::Filter::for_source(&source).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+        if !source.url.is_empty() {
+            ics::location(&source).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+        }
+    }
+    ics::Mappings::new(&settings.ics_mappings).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    settings.check_mappings().map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    ics::Feed::new(&settings.timesheet_feed).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    Ok(())
+}
+
 pub async fn set_settings(State(store): State<Arc<Store>>, Json(mut payload): Json<Settings>) -> Result<(), (StatusCode, String)> {
     tracing::info!("Set settings");
 
-    let path = store.upload_dir.join("settings.json");
+    validate_settings(&payload)?;
+    if payload.last_updated.is_none() {
+        payload.last_updated = Some(get_now());
+    }
+    store.storage.set_settings(&payload).await.map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))
+}
 
-    async {
-        let mut file = File::create(path).await?;
-        if payload.last_updated.is_none() {
-            payload.last_updated = Some(get_now());
-        }
-        let contents = serde_json::to_vec(&payload)?;
-        file.write_all(&contents).await?;
-        file.flush().await?;
-        Ok::<_, io::Error>(())
-    }.await.map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))
+#[derive(Deserialize, Default)]
+pub struct IcsRangeParams {
+    /// First day (`YYYY-MM-DD`) of the entries. Defaults to the start of the configured window.
+    from: Option<String>,
+    /// Last day (`YYYY-MM-DD`, inclusive) of the entries. Defaults to the end of the configured window.
+    to: Option<String>,
+    /// Time zone of the user (ie `Europe/Berlin`), in which the days are cut. Defaults to UTC.
+    tz: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
-struct IcsMeta {
-    timestamp: u64,
+impl IcsRangeParams {
+    /// The requested days, with the month and day of the path if given
+    fn days(&self, month: Option<u64>, day: Option<u64>) -> Result<ics::DayFilter, (StatusCode, String)> {
+        fn parse_day(value: &str) -> Result<chrono::NaiveDate, (StatusCode, String)> {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid date {value}: {err}")))
+        }
+
+        let zone = match &self.tz {
+            Some(tz) => tz.parse().map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid time zone {tz}: {err}")))?,
+            None => chrono_tz::UTC,
+        };
+        let days = ics::DayFilter {
+            zone,
+            first: self.from.as_deref().map(parse_day).transpose()?,
+            last: self.to.as_deref().map(parse_day).transpose()?,
+            month,
+            day,
+        };
+        if let (Some(first), Some(last)) = (days.first, days.last) {
+            if first > last {
+                return Err((StatusCode::BAD_REQUEST, "from must not be after to".to_string()));
+            }
+        }
+        Ok(days)
+    }
+
+    /// The configured window, or the requested days
+    fn range(window: &IcsWindow, now: u64, days: &ics::DayFilter) -> ics::TimeRange {
+        let mut range = ics::TimeRange::around(window, i64::try_from(now).unwrap_or(i64::MAX));
+        if let Some(first) = days.first {
+            range.start = days.start_of(first);
+        }
+        if let Some(last) = days.last {
+            range.end = days.start_of(last + chrono::Duration::days(1));
+        }
+        range
+    }
 }
 
-pub async fn fetch_ics_full(State(store): State<Arc<Store>>) -> Result<Json<Vec<ICSEntry>>, (StatusCode, String)> {
-    fetch_ics(None, None, store).await
+pub async fn fetch_ics_full(Query(params): Query<IcsRangeParams>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ics::ICSEntry>>, (StatusCode, String)> {
+    fetch_ics(None, None, params, store).await
 }
 
-pub async fn fetch_ics_month(Path(month): Path<u64>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ICSEntry>>, (StatusCode, String)> {
-    fetch_ics(Some(month), None, store).await
+pub async fn fetch_ics_month(Path(month): Path<u64>, Query(params): Query<IcsRangeParams>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ics::ICSEntry>>, (StatusCode, String)> {
+    fetch_ics(Some(month), None, params, store).await
 }
 
-pub async fn fetch_ics_month_day(Path((month, day)): Path<(u64, u64)>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ICSEntry>>, (StatusCode, String)> {
-    fetch_ics(Some(month), Some(day), store).await
+pub async fn fetch_ics_month_day(Path((month, day)): Path<(u64, u64)>, Query(params): Query<IcsRangeParams>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ics::ICSEntry>>, (StatusCode, String)> {
+    fetch_ics(Some(month), Some(day), params, store).await
 }
 
-async fn fetch_ics(month: Option<u64>, day: Option<u64>, store: Arc<Store>) -> Result<Json<Vec<ICSEntry>>, (StatusCode, String)> {
-    let settings: Json<Settings> = read_file(&store.upload_dir.join("settings.json")).await
-        .map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+async fn fetch_ics(month: Option<u64>, day: Option<u64>, params: IcsRangeParams, store: Arc<Store>) -> Result<Json<Vec<ics::ICSEntry>>, (StatusCode, String)> {
+    let settings = read_settings(&store).await?;
 
-    if settings.ics_url.is_empty() {
+    let sources = settings.calendar_sources();
+    if sources.is_empty() {
         return Err((StatusCode::NOT_FOUND, "No ICS URL set".to_string()));
     }
 
-    let path = store.upload_dir.join("ics.json");
-    let path_raw = store.upload_dir.join("ics_raw.json");
-    let date_path = store.upload_dir.join("ics_ts.json");
-    let mut ics_meta: IcsMeta = read_file(&date_path).await.unwrap_or_default().0;
+    let mappings = ics::Mappings::new(&settings.ics_mappings).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
     let now = get_now();
-    if ics_meta.timestamp + 24 * 60 * 60 >= now {
-        // Return cached value
-        let output = read_file::<Vec<ICSEntry>>(&path).await.map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?.0;
-        let unfiltered_entries = output.len();
-        let output = filter_ics_entries(output, month, day, &settings.ics_filter);
-        tracing::info!("Fetch ICS: Cached value: {}-{}. Entries: {} ({})", month.unwrap_or(100), day.unwrap_or(100), output.len(), unfiltered_entries);
-        return Ok(Json(output));
-    }
-
-    tracing::info!("Fetch ICS");
-
-    let (buf, status) = async {
-        let client = reqwest::Client::builder()
-            .https_only(true)
-            .timeout(Duration::from_secs(10))
-            .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/110.0")
-            .build()?;
-        let res = client.get(&settings.ics_url)
-            .header("Host", "outlook.office365.com").send().await?;
-
-        let status = res.status();
-        let buf = res.text().await?;
-        Ok::<_, Box<dyn std::error::Error>>((buf, status))
-    }.await.map_err(|err| {
-        tracing::error!("Failed to fetch ICS from {}: {:?}", &settings.ics_url, err);
-        return (StatusCode::NOT_FOUND, err.to_string());
-    })?;
-
-    if status != 200 {
-        tracing::info!("Fetch ICS failed {} {}", status, &buf);
-        return Err((status, buf));
-    }
-
-    async {
-        let mut v: Vec<IcalEvent> = vec![];
-
-        let ical = ical::IcalParser::new(buf.as_ref());
-        for line in ical {
-            if let Ok(mut entry) = line {
-                v.append(&mut entry.events);
-            }
+    let days = params.days(month, day)?;
+    let range = IcsRangeParams::range(&settings.ics_window, now, &days);
+    // All-day entries start at midnight UTC, which can be a day off the requested days in the zone of the user
+    let range = ics::TimeRange { start: range.start - 24 * 60 * 60, end: range.end + 24 * 60 * 60 };
+    let output = ics::fetch_sources(&store.storage, &sources, &mappings, range, &days, now).await?;
+    Ok(Json(output))
+}
+
+#[derive(Deserialize)]
+pub struct PreviewRequest {
+    rules: Vec<FilterRule>,
+    /// Only entries of the calendar source with this name
+    #[serde(default)]
+    source: Option<String>,
+}
+
+/// Cached entries with whether the given rules would keep them. The configured rules are not applied.
+///
+/// # Errors
+/// Responds with 422 if a rule is invalid and with 404 if the requested calendar source does not exist.
+pub async fn preview_ics(Query(params): Query<IcsRangeParams>, State(store): State<Arc<Store>>, Json(request): Json<PreviewRequest>) -> Result<Json<Vec<ics::PreviewEntry>>, (StatusCode, String)> {
+    let settings = read_settings(&store).await?;
+
+    let filter = ics::Filter::new(&request.rules).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    let mut sources = settings.calendar_sources();
+    if let Some(name) = &request.source {
+        sources.retain(|source| &source.name == name);
+        if sources.is_empty() {
+            return Err((StatusCode::NOT_FOUND, format!("No calendar source {name}")));
         }
+    }
 
+    let mappings = ics::Mappings::new(&settings.ics_mappings).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    let range = IcsRangeParams::range(&settings.ics_window, get_now(), &params.days(None, None)?);
+    Ok(Json(ics::preview_sources(&store.storage, &sources, &filter, &mappings, range).await?))
+}
 
-        let events = serde_json::to_string(&v)?;
-        let mut file = File::create(path_raw).await?;
-        file.write_all(events.as_ref()).await?;
-        file.flush().await?;
+/// Sync state of all calendar sources: last fetch, its outcome and the cached entries
+///
+/// # Errors
+/// Responds with 404 if no settings are stored.
+pub async fn ics_status(State(store): State<Arc<Store>>) -> Result<Json<Vec<ics::SourceStatus>>, (StatusCode, String)> {
+    let settings = read_settings(&store).await?;
 
-        let output = convert(v)?;
+    Ok(Json(ics::sources_status(&store.storage, &settings.calendar_sources()).await))
+}
 
-        let events = serde_json::to_string(&output)?;
+#[derive(Deserialize)]
+pub struct RefreshParams {
+    /// Only refresh the calendar source with this name
+    source: Option<String>,
+}
 
-        let mut file = File::create(path).await?;
-        file.write_all(events.as_ref()).await?;
-        file.flush().await?;
+/// Fetches the calendar sources again, regardless of their cache TTL
+///
+/// # Errors
+/// Responds with 404 if no settings are stored or there is no calendar source to refresh.
+pub async fn refresh_ics(Query(params): Query<RefreshParams>, State(store): State<Arc<Store>>) -> Result<Json<Vec<ics::RefreshResult>>, (StatusCode, String)> {
+    let settings = read_settings(&store).await?;
+
+    let mut sources = settings.calendar_sources();
+    if let Some(name) = &params.source {
+        sources.retain(|source| &source.name == name);
+        if sources.is_empty() {
+            return Err((StatusCode::NOT_FOUND, format!("No calendar source {name}")));
+        }
+    }
+    if sources.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "No ICS URL set".to_string()));
+    }
 
-        ics_meta.timestamp = now;
-        let mut file = File::create(date_path).await?;
-        file.write_all(serde_json::to_string(&ics_meta)?.as_ref()).await?;
-        file.flush().await?;
+    tracing::info!("Refresh ICS: {} sources", sources.len());
+    let now = get_now();
+    let range = ics::TimeRange::around(&settings.ics_window, i64::try_from(now).unwrap_or(i64::MAX));
+    Ok(Json(ics::refresh_sources(&store.storage, &sources, range, now).await))
+}
 
-        tracing::info!("Fetch ICS successful. Entries {}", output.len());
+#[derive(Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct ListTimesheets {
+    sheets: Vec<MonthSummary>,
+}
 
-        let output = filter_ics_entries(output, month, day, &settings.ics_filter);
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SortTimesheets {
+    #[default]
+    Date,
+    Modified,
+    BookedMinutes,
+}
 
-        Ok::<_, Box<dyn std::error::Error>>(Json(output))
-    }.await.map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))
+#[derive(Deserialize, Default)]
+pub struct ListTimesheetsParams {
+    year: Option<i32>,
+    #[serde(default)]
+    sort: SortTimesheets,
+    /// Sort in descending order
+    #[serde(default)]
+    desc: bool,
 }
 
-fn filter_ics_entries(entries: Vec<ICSEntry>, month: Option<u64>, day: Option<u64>, filter: &Vec<String>) -> Vec<ICSEntry> {
-    entries.into_iter().filter(|entry| {
-        let d = time::OffsetDateTime::from_unix_timestamp(entry.start).unwrap();
-        if let Some(month) = &month {
-            if let Ok(month) = Month::try_from(*month as u8) {
-                if d.month() != month {
-                    return false;
-                }
-            }
-        }
-        if let Some(day) = &day {
-            if d.day() != *day as u8 {
-                return false;
-            }
-        }
+/// Lists all stored timesheets, optionally only of one year (`?year=2023`).
+/// Sorted by `?sort=date|modified|booked_minutes`, add `&desc=true` for descending order.
+///
+/// # Errors
+/// Responds with 500 if the stored timesheets can not be read.
+pub async fn list_timesheets(Query(params): Query<ListTimesheetsParams>, State(store): State<Arc<Store>>) -> Result<Json<ListTimesheets>, (StatusCode, String)> {
+    tracing::info!("List timesheets");
 
-        for filter_entry in filter {
-            if entry.title.contains(filter_entry) {
-                return false;
-            }
-        }
+    let mut sheets = store.storage.list_months().await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    sheets.retain(|sheet| params.year.is_none_or(|year| sheet.year == year));
+
+    match params.sort {
+        SortTimesheets::Date => sheets.sort_by_key(|sheet| (sheet.year, sheet.month)),
+        SortTimesheets::Modified => sheets.sort_by_key(|sheet| sheet.modified),
+        SortTimesheets::BookedMinutes => sheets.sort_by_key(|sheet| sheet.booked_minutes),
+    }
+    if params.desc {
+        sheets.reverse();
+    }
+
+    Ok(Json(ListTimesheets { sheets }))
+}
 
-        true
-    }).collect()
+/// Checks an `If-Match` request header against the current version of a timesheet.
+/// Requests without the header always match. Weak tags (`W/"3"`) never match, `If-Match` uses the strong comparison.
+fn if_match(headers: &HeaderMap, current: Option<&OneMonth>) -> bool {
+    let Some(value) = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let Some(current) = current else {
+        return false;
+    };
+    let etag = current.etag();
+    value.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag)
 }
 
-#[derive(Serialize, Deserialize, Default, Clone)]
-pub struct ICSEntry {
-    desc: String,
-    uid: String,
-    title: String,
-    /// Unix timestamp in seconds
-    start: i64,
-    /// Duration in seconds
-    duration: i64,
-    confirmed: bool,
-    /// Out of office
-    oof: bool,
+fn etag_header(month: &OneMonth) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, month.etag().parse().unwrap());
+    headers
 }
 
+pub async fn get_timesheet(Path(date): Path<MonthKey>, State(store): State<Arc<Store>>, request_headers: HeaderMap) -> impl IntoResponse {
+    tracing::info!("Get timesheet {}", date);
 
-fn convert_ics_date_unix(mut input: String) -> i64 {
-    use time::format_description::well_known::Iso8601;
-    use time::PrimitiveDateTime;
+    let filename = format!("{date}.timesheet");
 
-    if !input.contains('T') {
-        input += "T000000Z";
+    let month = match store.storage.get_month(&date).await {
+        Ok(Some(result)) => result,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, format!("File not found: {filename}"))),
+        Err(err) => return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    };
+
+    if !if_match(&request_headers, Some(&month)) {
+        return Err((StatusCode::PRECONDITION_FAILED, format!("Timesheet {date} has changed")));
     }
 
-    if let Ok(date) = PrimitiveDateTime::parse(&input, &Iso8601::DEFAULT) {
-        date.assume_utc().unix_timestamp()
-    } else { 0 }
+    let mut headers = etag_header(&month);
+    headers.insert(header::CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    headers.insert(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\"").parse().unwrap());
+
+    let contents = serde_json::to_vec(&month).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok((headers, contents))
 }
 
-fn convert(input: Vec<IcalEvent>) -> Result<Vec<ICSEntry>, Box<dyn std::error::Error>> {
-    use rrule::{RRule, Tz, RRuleSet};
-    use chrono::{NaiveDateTime, DateTime, Utc};
+/// Checks a timesheet against the model and the projects and tags of the settings
+async fn validate_timesheet(store: &Store, date: &MonthKey, month: &OneMonth) -> Result<(), Response> {
+    // Projects and tags can only be checked if settings have been stored already
+    let settings = store.storage.get_settings().await.ok().flatten();
+    let errors = month.validate(
+        settings.as_ref().map(|s| s.projects.as_slice()),
+        settings.as_ref().map(|s| s.tags.as_slice()),
+    );
+    if !errors.is_empty() {
+        tracing::info!("Rejected timesheet {}: {} invalid fields", date, errors.len());
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationErrors::new(errors))).into_response());
+    }
+    Ok(())
+}
 
-    let mut output = Vec::<ICSEntry>::new();
-    let mut entries_map = HashSet::<i64>::new();
+#[derive(Deserialize, Default)]
+pub struct SetTimesheetParams {
+    /// Enables merge mode: The `change_id` the client started editing from
+    base: Option<u64>,
+}
 
-    let mut end = 0;
+/// Stores a timesheet.
+///
+/// Uploads must carry a `change_id` newer than the stored one, otherwise
+/// 409 Conflict is returned together with the current server copy. An `If-Match` header is checked
+/// against the `ETag` of the stored timesheet (412 Precondition Failed on mismatch).
+///
+/// In merge mode (`?base=<change_id>`) a stale upload is merged per day and entry with the current
+/// server copy, using the stored revision `base` as common ancestor. Only if the same entry was
+/// changed on both sides a 409 with the list of conflicts is returned.
+///
+/// Responds with the stored timesheet.
+pub async fn set_timesheet(Path(date): Path<MonthKey>, Query(params): Query<SetTimesheetParams>, State(store): State<Arc<Store>>, request_headers: HeaderMap, body: Bytes) -> Result<(HeaderMap, Json<OneMonth>), Response> {
+    tracing::info!("Set timesheet {}", date);
 
-    let min_start = std::time::SystemTime::now().duration_since(UNIX_EPOCH)? - Duration::from_secs(60 * 60 * 24 * 70);
-    let min_start = min_start.as_secs();
+    let mut month: OneMonth = serde_json::from_slice(&body)
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationErrors::parse_error(&err))).into_response())?;
 
-    let chrono_now = Utc::now();
-    let offset = Tz::UTC.offset_from_utc_date(&chrono_now.date_naive());
-    let min_chrono_date = DateTime::<Tz>::from_utc(chrono_now.naive_utc() - chrono::Duration::seconds(60 * 60 * 24 * 70), offset);
+    if (month.year, month.month) != (date.year, date.month) {
+        let errors = vec![FieldError::new("month", format!("Timesheet is for {}-{:02}, but was uploaded as {}", month.year, month.month, date.iso()))];
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationErrors::new(errors))).into_response());
+    }
 
-    for entry in input {
-        let mut recurring: Option<String> = None;
-        let mut new_entry = ICSEntry::default();
-        for prop in entry.properties {
-            match &prop.name[..] {
-                "DESCRIPTION" => { new_entry.desc = prop.value.unwrap_or_default(); }
-                "UID" => { new_entry.uid = prop.value.unwrap_or_default(); }
-                "SUMMARY" => { new_entry.title = prop.value.unwrap_or_default(); }
-                "DTSTART" => { new_entry.start = prop.value.and_then(|v| Some(convert_ics_date_unix(v))).unwrap_or_default(); }
-                "DTEND" => { end = prop.value.and_then(|v| Some(convert_ics_date_unix(v))).unwrap_or_default(); }
-                "X-MICROSOFT-CDO-BUSYSTATUS" => {
-                    let v = prop.value.unwrap_or_default();
-                    new_entry.confirmed = v == "BUSY";
-                    new_entry.oof = v == "OOF";
-                }
-                "RRULE" => { recurring = prop.value; }
-                _ => {}
-            }
-        }
-        if end != 0 {
-            new_entry.duration = end - new_entry.start;
-        }
+    validate_timesheet(&store, &date, &month).await?;
 
-        if let Some(index) = new_entry.desc.find("________________________________________________________________________________") {
-            new_entry.desc.truncate(index);
-        }
+    let internal_error = |err: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+
+    let _lock = store.lock_month(date).await;
+    let current = store.storage.get_month(&date).await.map_err(internal_error)?;
+
+    if !if_match(&request_headers, current.as_ref()) {
+        tracing::info!("Rejected timesheet {}: If-Match does not match", date);
+        return Err(current.map_or_else(
+            || (StatusCode::PRECONDITION_FAILED, format!("Timesheet {date} does not exist")).into_response(),
+            |current| (StatusCode::PRECONDITION_FAILED, etag_header(&current), Json(current)).into_response(),
+        ));
+    }
 
-        // Recurring rules
-        if let Some(recurring) = &recurring {
-            let date_time = NaiveDateTime::from_timestamp_opt(new_entry.start, 0).unwrap();
-            let offset = Tz::UTC.offset_from_utc_date(&date_time.date());
-            let date_time = DateTime::<Tz>::from_utc(date_time, offset);
-
-            let rrule = RRule::from_str(recurring.as_str()).and_then(|rrule| rrule.validate(date_time));
-            if let Ok(rrule) = rrule
-            {
-                tracing::info!("Recurring entry {} - {}", recurring, new_entry.title);
-                let rrule_set = RRuleSet::new(date_time).rrule(rrule);
-                let rrules = rrule_set.into_iter();
-                for i in rrules {
-                    if i > chrono_now { break; }
-                    if i < min_chrono_date { continue; }
-                    new_entry.start = i.timestamp();
-                    if entries_map.insert(new_entry.start) {
-                        tracing::info!("Recurring entry {} ({})", i, new_entry.start);
-                        output.push(new_entry.clone());
+    if let Some(current) = current {
+        match params.base {
+            Some(base) if base != current.change_id => {
+                let Some(base) = store.storage.find_revision(&date, base).await.map_err(internal_error)? else {
+                    tracing::info!("Rejected timesheet {}: No revision with change_id {} to merge with", date, base);
+                    return Err((StatusCode::CONFLICT, etag_header(&current), Json(current)).into_response());
+                };
+                month = match merge::three_way(&base, &current, &month) {
+                    Ok(merged) => merged,
+                    Err(conflicts) => {
+                        tracing::info!("Rejected timesheet {}: {} merge conflicts", date, conflicts.len());
+                        let body = MergeConflicts { error: "Merge conflict".into(), conflicts, server: current.clone() };
+                        return Err((StatusCode::CONFLICT, etag_header(&current), Json(body)).into_response());
                     }
-                }
-            } else {
-                tracing::warn!("Failed to parse recurring entry {} - {}", recurring, new_entry.title);
+                };
+                tracing::info!("Merged timesheet {} with change_id {}", date, current.change_id);
+                validate_timesheet(&store, &date, &month).await?;
             }
-        } else {
-            if new_entry.start >= min_start as i64 && entries_map.insert(new_entry.start) {
-                tracing::info!("Normal entry {}", new_entry.start);
-                output.push(new_entry);
+            // Based on the current version, the upload only has to get a newer change_id
+            Some(_) => month.change_id = month.change_id.max(current.change_id + 1),
+            None if month.change_id <= current.change_id => {
+                tracing::info!("Rejected timesheet {}: change_id {} is not newer than {}", date, month.change_id, current.change_id);
+                return Err((StatusCode::CONFLICT, etag_header(&current), Json(current)).into_response());
             }
+            None => {}
         }
     }
 
-    Ok(output)
+    store.storage.put_month(&date, &month).await.map_err(internal_error)?;
+
+    Ok((etag_header(&month), Json(month)))
 }
 
-#[tokio::test]
-async fn convert_test() -> Result<(), Box<dyn std::error::Error>> {
-    let path = std::path::PathBuf::from_str("/home/david/Entwicklung/timesheet-web/time-sheets")?;
-    let input_path = path.join("ics.ics");
-    let output_path = path.join("ics_converted.ics");
+/// Deletes a timesheet. The deleted version is kept as revision and can be restored.
+pub async fn delete_timesheet(Path(date): Path<MonthKey>, State(store): State<Arc<Store>>) -> Result<(), (StatusCode, String)> {
+    tracing::info!("Delete timesheet {}", date);
 
-    let mut contents = vec![];
-    let mut file = File::open(input_path).await?;
-    file.read_to_end(&mut contents).await?;
+    let _lock = store.lock_month(date).await;
+    let deleted = store.storage.delete_month(&date).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    if !deleted {
+        return Err((StatusCode::NOT_FOUND, format!("File not found: {date}.timesheet")));
+    }
 
-    let input: Vec<IcalEvent> = serde_json::from_slice(&contents)?;
-    let output = convert(input)?;
-    let output = serde_json::to_vec_pretty(&output)?;
+    Ok(())
+}
 
-    let mut file = File::create(output_path).await?;
-    file.write_all(output.as_ref()).await?;
-    file.flush().await?;
+/// Revisions of a timesheet, oldest first
+///
+/// # Errors
+/// Responds with 500 if the revisions can not be read.
+pub async fn list_revisions(Path(date): Path<MonthKey>, State(store): State<Arc<Store>>) -> Result<Json<Vec<RevisionInfo>>, (StatusCode, String)> {
+    tracing::info!("List revisions {}", date);
 
-    Ok(())
+    let revisions = store.storage.list_revisions(&date).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    Ok(Json(revisions))
 }
 
-#[derive(Serialize, Deserialize, Default)]
-#[serde(transparent)]
-pub struct ListTimesheets {
-    sheets: Vec<String>,
+async fn get_revision_or_404(store: &Store, date: &MonthKey, revision: u64) -> Result<OneMonth, (StatusCode, String)> {
+    store.storage.get_revision(date, revision).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Revision {revision} of timesheet {date} not found")))
 }
 
-pub async fn list_timesheets() -> Json<ListTimesheets> {
-    tracing::info!("List timesheets");
-    let s = ListTimesheets::default();
-    Json(s)
+/// # Errors
+/// Responds with 404 if the timesheet has no such revision.
+pub async fn get_revision(Path((date, revision)): Path<(MonthKey, u64)>, State(store): State<Arc<Store>>) -> Result<Json<OneMonth>, (StatusCode, String)> {
+    tracing::info!("Get revision {} of timesheet {}", revision, date);
+
+    Ok(Json(get_revision_or_404(&store, &date, revision).await?))
 }
 
-pub async fn get_timesheet(Path(date): Path<String>, State(store): State<Arc<Store>>) -> impl IntoResponse {
-    if date.is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "The timesheet date, ie 2022-12, must be given!".to_string()));
-    }
+/// Per-day differences between two revisions. Only changed days are returned.
+///
+/// # Errors
+/// Responds with 404 if one of the revisions does not exist.
+pub async fn diff_revisions(Path((date, from, to)): Path<(MonthKey, u64, u64)>, State(store): State<Arc<Store>>) -> Result<Json<Vec<DayDiff>>, (StatusCode, String)> {
+    tracing::info!("Diff revisions {} and {} of timesheet {}", from, to, date);
 
-    tracing::info!("Get timesheet {}", date);
+    let from = get_revision_or_404(&store, &date, from).await?;
+    let to = get_revision_or_404(&store, &date, to).await?;
+    Ok(Json(diff_days(&from, &to)))
+}
 
-    let filename = format!("{}.timesheet", date);
-    let path = store.upload_dir.join(&filename);
+/// Makes the given revision the current version of a timesheet. The restored timesheet gets a new
+/// `change_id`, so that clients holding the replaced version notice the change.
+///
+/// # Errors
+/// Responds with 404 if the revision does not exist and with 500 if it can not be stored.
+pub async fn restore_revision(Path((date, revision)): Path<(MonthKey, u64)>, State(store): State<Arc<Store>>) -> Result<(HeaderMap, Json<OneMonth>), (StatusCode, String)> {
+    tracing::info!("Restore revision {} of timesheet {}", revision, date);
+
+    let _lock = store.lock_month(date).await;
+    let mut month = get_revision_or_404(&store, &date, revision).await?;
+    let current = store.storage.get_month(&date).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let latest_change_id = current.map_or(0, |current| current.change_id);
+    month.change_id = month.change_id.max(latest_change_id) + 1;
+
+    store.storage.put_month(&date, &month).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok((etag_header(&month), Json(month)))
+}
 
-    // `File` implements `AsyncRead`
-    let file = match File::open(path).await {
-        Ok(file) => file,
-        Err(err) => return Err((StatusCode::NOT_FOUND, format!("File not found: {}", err))),
-    };
-    // convert the `AsyncRead` into a `Stream`
-    let stream = ReaderStream::new(file);
-    // convert the `Stream` into an `axum::body::HttpBody`
-    let body = StreamBody::new(stream);
+/// Reads settings and all timesheets for an export or as base for an import
+async fn read_account(store: &Store) -> Result<archive::Account, (StatusCode, String)> {
+    let internal_error = |err: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
 
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
-    headers.insert(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename).parse().unwrap());
+    let mut account = archive::Account {
+        settings: store.storage.get_settings().await.map_err(internal_error)?,
+        ..archive::Account::default()
+    };
+    for summary in store.storage.list_months().await.map_err(internal_error)? {
+        if let Some(month) = store.storage.get_month(&summary.date).await.map_err(internal_error)? {
+            account.months.insert(summary.date, month);
+        }
+    }
+    Ok(account)
+}
 
-    Ok((headers, body))
+#[derive(Deserialize, Default)]
+pub struct TimesheetFeedParams {
+    /// Overrides the `times` of the feed settings
+    times: Option<FeedTimes>,
 }
 
-pub async fn set_timesheet(Path(date): Path<String>, State(store): State<Arc<Store>>, body: BodyStream) -> Result<(), (StatusCode, String)> {
-    if date.is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "The timesheet date, ie 2022-12, must be given!".to_string()));
+/// All timesheets as ICS calendar, one event per entry (`?times=sequential|imported`)
+///
+/// # Errors
+/// Responds with 422 if the stored feed settings are invalid.
+pub async fn timesheets_ics(Query(params): Query<TimesheetFeedParams>, State(store): State<Arc<Store>>) -> Result<Response, (StatusCode, String)> {
+    tracing::info!("Timesheets ICS feed");
+
+    let account = read_account(&store).await?;
+    let settings = account.settings.map(|settings| settings.timesheet_feed).unwrap_or_default();
+    let mut feed = ics::Feed::new(&settings).map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+    if let Some(times) = params.times {
+        feed = feed.with_times(times);
     }
+    let months: Vec<OneMonth> = account.months.into_values().collect();
+    let body = feed.render(&months, i64::try_from(get_now()).unwrap_or(i64::MAX));
+    Ok(([(header::CONTENT_TYPE, "text/calendar; charset=utf-8")], body).into_response())
+}
 
-    tracing::info!("Set timesheet {}", date);
+#[derive(Deserialize, Default)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ArchiveFormat,
+    /// Remove credentials from the settings
+    #[serde(default)]
+    redact: bool,
+}
 
-    let body_with_io_error = body.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-    let body_reader = StreamReader::new(body_with_io_error);
-    futures::pin_mut!(body_reader);
+/// Downloads settings and all timesheets as archive (`?format=zip|tar_gz`, `&redact=true` to leave out credentials)
+///
+/// # Errors
+/// Responds with 500 if the stored data can not be read or the archive can not be written.
+pub async fn export_account(Query(params): Query<ExportParams>, State(store): State<Arc<Store>>) -> Result<Response, (StatusCode, String)> {
+    tracing::info!("Export account");
 
-    async {
-        let path = store.upload_dir.join(format!("{}.timesheet", date));
-        let mut file = BufWriter::new(File::create(path).await?);
-        tokio::io::copy(&mut body_reader, &mut file).await?;
-        Ok::<_, io::Error>(())
-    }.await.map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let account = read_account(&store).await?;
+    let now = get_now();
+    let archive = tokio::task::spawn_blocking(move || archive::write(&account, params.format, params.redact, now))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    let date = chrono::Utc::now().format("%Y-%m-%d");
+    let disposition = format!("attachment; filename=\"timesheets-{date}.{}\"", params.format.extension());
+    Ok((
+        [(header::CONTENT_TYPE, params.format.content_type().to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        archive,
+    ).into_response())
+}
 
-    Ok(())
+#[derive(Deserialize, Default)]
+pub struct ImportParams {
+    #[serde(default)]
+    mode: ImportMode,
+    /// Only report what would change
+    #[serde(default)]
+    dry_run: bool,
 }
 
-pub async fn delete_timesheet(Path(date): Path<String>, State(store): State<Arc<Store>>) -> Result<(), (StatusCode, String)> {
-    if date.is_empty() {
-        return Err((StatusCode::BAD_REQUEST, "The timesheet date, ie 2022-12, must be given!".to_string()));
+/// Imports an archive created by [`export_account`].
+///
+/// `?mode=replace` (default) replaces all data, `?mode=merge` only adds missing and newer timesheets.
+/// With `&dry_run=true` nothing is written.
+///
+/// # Errors
+/// Responds with 422 if the archive is invalid, a checksum does not match or the imported settings are
+/// rejected like by [`set_settings`], and with the list of invalid fields if a timesheet to write does not
+/// pass validation against the resulting settings.
+pub async fn import_account(Query(params): Query<ImportParams>, State(store): State<Arc<Store>>, body: Bytes) -> Result<Json<archive::ImportReport>, Response> {
+    tracing::info!("Import account ({:?}, dry-run: {})", params.mode, params.dry_run);
+
+    let (manifest, imported) = tokio::task::spawn_blocking(move || archive::read(&body))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())?
+        .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err).into_response())?;
+
+    let current = read_account(&store).await.map_err(IntoResponse::into_response)?;
+    let plan = archive::plan(&current, manifest, imported, params.mode, params.dry_run);
+    if let Some(settings) = &plan.settings {
+        validate_settings(settings).map_err(IntoResponse::into_response)?;
+    }
+    let errors = plan.validate(&current);
+    if !errors.is_empty() {
+        tracing::info!("Rejected import: {} invalid fields", errors.len());
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, Json(ValidationErrors::new(errors))).into_response());
+    }
+    if params.dry_run {
+        return Ok(Json(plan.report));
     }
 
-    tracing::info!("Delete timesheet {}", date);
+    let internal_error = |err: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    if let Some(mut settings) = plan.settings {
+        settings.last_updated = Some(get_now());
+        store.storage.set_settings(&settings).await.map_err(internal_error)?;
+    }
+    for (date, month) in &plan.put {
+        let _lock = store.lock_month(*date).await;
+        store.storage.put_month(date, month).await.map_err(internal_error)?;
+    }
+    for date in &plan.delete {
+        let _lock = store.lock_month(*date).await;
+        store.storage.delete_month(date).await.map_err(internal_error)?;
+    }
+    Ok(Json(plan.report))
+}
 
-    let path = store.upload_dir.join(format!("{}.timesheet", date));
-    fs::remove_file(path).map_err(|err| (StatusCode::NOT_FOUND, err.to_string()))?;
+#[cfg(test)]
+mod tests {
+    use std::path::Path as FilePath;
 
-    Ok(())
+    use axum::extract::FromRequestParts;
+
+    use super::*;
+    use crate::settings::MappingRule;
+    use crate::storage::{file::FileStorage, sqlite::SqliteStorage, RetentionPolicy};
+    use crate::timesheet::{DayEntry, OneDay};
+
+    async fn memory_store() -> Arc<Store> {
+        let storage = SqliteStorage::open(FilePath::new(":memory:"), RetentionPolicy::default()).await.unwrap();
+        Arc::new(Store::new("token".into(), Arc::new(storage)))
+    }
+
+    fn april_key() -> MonthKey {
+        "2023_4".parse().unwrap()
+    }
+
+    fn april(change_id: u64) -> OneMonth {
+        OneMonth { days: vec![OneDay::default(); 30], year: 2023, month: 4, created: 1, change_id }
+    }
+
+    async fn upload(store: &Arc<Store>, month: &OneMonth, if_match: Option<&str>) -> Response {
+        let mut headers = HeaderMap::new();
+        if let Some(tags) = if_match {
+            headers.insert(header::IF_MATCH, tags.parse().unwrap());
+        }
+        let body = Bytes::from(serde_json::to_vec(month).unwrap());
+        set_timesheet(Path(april_key()), Query(SetTimesheetParams::default()), State(store.clone()), headers, body).await.into_response()
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_uploads() {
+        let store = memory_store().await;
+
+        let response = upload(&store, &april(1), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"1\"");
+        let response = get_timesheet(Path(april_key()), State(store.clone()), HeaderMap::new()).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"1\"");
+
+        // The change_id must be newer than the stored one
+        let response = upload(&store, &april(1), None).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers()[header::ETAG], "\"1\"");
+
+        // If-Match compares strongly
+        assert_eq!(upload(&store, &april(2), Some("\"0\"")).await.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(upload(&store, &april(2), Some("W/\"1\"")).await.status(), StatusCode::PRECONDITION_FAILED);
+        let response = upload(&store, &april(2), Some("\"0\", \"1\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "\"2\"");
+
+        // Of two uploads made from the same version only the first one is stored
+        let month = april(3);
+        let (first, second) = tokio::join!(upload(&store, &month, Some("\"2\"")), upload(&store, &month, Some("\"2\"")));
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::PRECONDITION_FAILED]);
+    }
+
+    async fn listed(store: &Arc<Store>, query: &str) -> Vec<MonthSummary> {
+        let request = axum::http::Request::get(format!("/api/timesheets?{query}")).body(()).unwrap();
+        let query = Query::from_request_parts(&mut request.into_parts().0, &()).await.unwrap();
+        let Json(list) = list_timesheets(query, State(store.clone())).await.unwrap();
+        list.sheets
+    }
+
+    #[tokio::test]
+    async fn lists_timesheets() {
+        let dir = std::env::temp_dir().join(format!("timesheet-list-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = FileStorage::new(dir.clone(), RetentionPolicy::default()).await.unwrap();
+        let store = Arc::new(Store::new("token".into(), Arc::new(storage)));
+
+        // Date, booked minutes per day and modification time
+        for (date, durations, modified) in [("2023_4", vec![90], 300), ("2022_12", vec![100, 50], 200), ("2023_5", vec![120], 100)] {
+            let date: MonthKey = date.parse().unwrap();
+            let mut month = OneMonth { days: vec![OneDay::default(); 28], year: date.year, month: date.month, created: 1, change_id: 1 };
+            for (day, duration) in durations.into_iter().enumerate() {
+                month.days[day].entries.push(DayEntry { duration, ..DayEntry::default() });
+            }
+            store.storage.put_month(&date, &month).await.unwrap();
+            std::fs::File::options().write(true).open(dir.join(format!("{date}.timesheet"))).unwrap()
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified)).unwrap();
+        }
+        let dates = |sheets: Vec<MonthSummary>| sheets.iter().map(|sheet| sheet.date.to_string()).collect::<Vec<_>>();
+
+        let sheets = listed(&store, "").await;
+        assert_eq!(sheets.iter().map(|sheet| (sheet.booked_minutes, sheet.filled_days, sheet.modified)).collect::<Vec<_>>(), [(150, 2, 200), (90, 1, 300), (120, 1, 100)]);
+        assert_eq!(dates(sheets), ["2022_12", "2023_4", "2023_5"]);
+        assert_eq!(dates(listed(&store, "year=2023").await), ["2023_4", "2023_5"]);
+        assert_eq!(dates(listed(&store, "year=2021").await), Vec::<String>::new());
+        assert_eq!(dates(listed(&store, "sort=modified").await), ["2023_5", "2022_12", "2023_4"]);
+        assert_eq!(dates(listed(&store, "sort=booked_minutes").await), ["2023_4", "2023_5", "2022_12"]);
+        assert_eq!(dates(listed(&store, "sort=booked_minutes&desc=true").await), ["2022_12", "2023_5", "2023_4"]);
+        assert_eq!(dates(listed(&store, "year=2023&sort=date&desc=true").await), ["2023_5", "2023_4"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cuts_requested_days_in_the_zone_of_the_user() {
+        let params = IcsRangeParams { from: Some("2023-11-14".into()), to: Some("2023-11-14".into()), tz: Some("Europe/Berlin".into()) };
+        let range = IcsRangeParams::range(&IcsWindow::default(), 0, &params.days(None, None).unwrap());
+        // 2023-11-13 23:00 to 2023-11-14 23:00 UTC
+        assert_eq!((range.start, range.end), (1_699_916_400, 1_700_002_800));
+
+        let unknown = IcsRangeParams { tz: Some("Mars/Olympus".into()), ..params };
+        assert_eq!(unknown.days(None, None).unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_mapping_targets() {
+        let store = memory_store().await;
+        let rule = MappingRule { project: "Falco".into(), tags: vec!["meeting".into()], ..MappingRule::default() };
+        let mut settings = Settings { projects: vec!["Falco".into()], ics_mappings: vec![rule], ..Settings::default() };
+
+        let (status, message) = set_settings(State(store.clone()), Json(settings.clone())).await.unwrap_err();
+        assert_eq!((status, message.as_str()), (StatusCode::UNPROCESSABLE_ENTITY, "Mapping rule 1: Unknown tag \"meeting\""));
+        settings.tags.push("meeting".into());
+        set_settings(State(store.clone()), Json(settings)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_imported_settings_like_uploaded_ones() {
+        let store = memory_store().await;
+        let rule = MappingRule { project: "Falco".into(), ..MappingRule::default() };
+        let settings = Settings { ics_mappings: vec![rule], ..Settings::default() };
+        let account = archive::Account { settings: Some(settings), ..archive::Account::default() };
+        let body = Bytes::from(archive::write(&account, ArchiveFormat::Zip, false, 0).unwrap());
+
+        let response = import_account(Query(ImportParams::default()), State(store.clone()), body).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(store.storage.get_settings().await.unwrap(), None);
+    }
 }
@@ -5,12 +5,16 @@ use axum::{
     Router,
 };
 use std::{sync::Arc};
+use axum::extract::DefaultBodyLimit;
 use axum::handler::HandlerWithoutStateExt;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tower_http::cors::{Any, CorsLayer};
 
 pub mod api;
 
+/// Maximum size of an uploaded archive for `/api/import`
+const IMPORT_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
 use crate::{
     middlewares,
     store::{Store},
@@ -31,24 +35,81 @@ async fn handle_404() -> (StatusCode, &'static str) {
 pub fn backend(
     state: Arc<Store>
 ) -> Router {
+    // Calendar apps subscribe to the feed with basic auth, all other routes only take the bearer token
+    let feed = Router::new()
+        .route("/api/timesheets.ics", get(api::timesheets_ics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            middlewares::feed_auth,
+        ));
+
     Router::new()
         .route("/api", get(api::handler))
         .route("/api/settings", get(api::get_settings))
         .route("/api/settings", post(api::set_settings))
         .route("/api/fetch_ics", get(api::fetch_ics_full))
+        .route("/api/fetch_ics/refresh", post(api::refresh_ics))
+        .route("/api/fetch_ics/status", get(api::ics_status))
+        .route("/api/fetch_ics/preview", post(api::preview_ics))
         .route("/api/fetch_ics/:month", get(api::fetch_ics_month))
         .route("/api/fetch_ics/:month/:day", get(api::fetch_ics_month_day))
         .route("/api/timesheets", get(api::list_timesheets))
         .route("/api/timesheets/:date", get(api::get_timesheet))
         .route("/api/timesheets/:date", post(api::set_timesheet))
         .route("/api/timesheets/:date", delete(api::delete_timesheet))
+        .route("/api/timesheets/:date/revisions", get(api::list_revisions))
+        .route("/api/timesheets/:date/revisions/:revision", get(api::get_revision))
+        .route("/api/timesheets/:date/revisions/:revision/restore", post(api::restore_revision))
+        .route("/api/timesheets/:date/diff/:from/:to", get(api::diff_revisions))
+        .route("/api/export", get(api::export_account))
+        .route("/api/import", post(api::import_account).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)))
         .route_layer(middleware::from_fn_with_state(
             state.clone(),
             middlewares::auth,
         ))
+        .merge(feed)
         .route_layer(CorsLayer::new()
             .allow_origin(Any)
             .allow_headers(Any)
             .allow_methods(Any))
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use axum::http::header;
+    use reqwest::RequestBuilder;
+
+    use super::*;
+    use crate::storage::{sqlite::SqliteStorage, RetentionPolicy};
+
+    #[tokio::test]
+    async fn feed_takes_basic_auth() {
+        let storage = SqliteStorage::open(Path::new(":memory:"), RetentionPolicy::default()).await.unwrap();
+        let app = backend(Arc::new(Store::new("secret".into(), Arc::new(storage))));
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let client = reqwest::Client::new();
+        let send = |request: RequestBuilder| async move {
+            let response = request.send().await.unwrap();
+            (response.status().as_u16(), response.headers().get(header::WWW_AUTHENTICATE).map(|v| v.to_str().unwrap().to_string()))
+        };
+        let feed = || client.get(format!("{base}/api/timesheets.ics"));
+        let challenge = Some("Basic realm=\"timesheets\"".to_string());
+
+        assert_eq!(send(feed().basic_auth("calendar", Some("secret"))).await, (200, None));
+        assert_eq!(send(feed().bearer_auth("secret")).await, (200, None));
+        assert_eq!(send(feed().basic_auth("calendar", Some("wrong"))).await, (401, challenge.clone()));
+        assert_eq!(send(feed().header(header::AUTHORIZATION, "Basic c2VjcmV0*")).await, (401, challenge.clone()));
+        assert_eq!(send(feed()).await, (401, challenge));
+
+        // Basic auth is only accepted by the feed
+        let timesheets = || client.get(format!("{base}/api/timesheets"));
+        assert_eq!(send(timesheets().basic_auth("calendar", Some("secret"))).await, (401, None));
+        assert_eq!(send(timesheets().bearer_auth("secret")).await, (200, None));
+    }
+}
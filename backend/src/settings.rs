@@ -0,0 +1,342 @@
+use chrono::Weekday;
+use serde::{Deserialize, Serialize};
+
+/// Account settings, shared by all clients
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub ics_url: String,
+    pub ics_filter: Vec<String>,
+    pub projects: Vec<String>,
+    pub tags: Vec<String>,
+    pub name: String,
+    pub company: String,
+    pub client: String,
+    pub gitlab_url: String,
+    pub gitlab_access_token: String,
+    pub last_updated: Option<u64>,
+    /// Calendars that are merged into the ICS entries, in addition to the legacy `ics_url`
+    #[serde(default)]
+    pub calendars: Vec<CalendarSource>,
+    /// Time range of ICS entries if a request does not ask for one
+    #[serde(default)]
+    pub ics_window: IcsWindow,
+    /// Filter rules for the entries of all calendars
+    #[serde(default)]
+    pub ics_rules: Vec<FilterRule>,
+    /// Rules that suggest projects and tags for ICS entries
+    #[serde(default)]
+    pub ics_mappings: Vec<MappingRule>,
+    /// Rendering of the timesheets as ICS feed
+    #[serde(default)]
+    pub timesheet_feed: TimesheetFeed,
+}
+
+/// Time range around now, in days
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct IcsWindow {
+    pub past_days: u32,
+    pub future_days: u32,
+}
+
+impl Default for IcsWindow {
+    fn default() -> Self {
+        Self { past_days: 70, future_days: 0 }
+    }
+}
+
+/// Times of the events of the timesheet feed. Entries have a duration only, their time of day is made up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct TimesheetFeed {
+    /// Time of day (`HH:MM`) of the first entry of a day
+    pub day_start: String,
+    /// Time zone of `day_start`, UTC if not set
+    pub time_zone: Option<String>,
+    pub times: FeedTimes,
+}
+
+impl Default for TimesheetFeed {
+    fn default() -> Self {
+        Self { day_start: "09:00".to_string(), time_zone: None, times: FeedTimes::default() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedTimes {
+    /// The entries of a day follow each other from `day_start`
+    #[default]
+    Sequential,
+    /// Entries imported from a calendar keep the time of their event, the others follow each other from `day_start`
+    Imported,
+}
+
+/// A published calendar (ICS url)
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct CalendarSource {
+    /// Unique name, entries are tagged with it
+    pub name: String,
+    /// `https://`, `webcal://` or `file://` url of the calendar, or `http://` with `allow_http`
+    pub url: String,
+    #[serde(default)]
+    pub kind: SourceKind,
+    /// CalDAV: display name or last path segment of the calendar to query, all calendars of the account if empty
+    #[serde(default)]
+    pub calendar: String,
+    /// HTTP basic auth, if not empty
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    /// HTTP bearer token, used instead of basic auth if not empty
+    #[serde(default)]
+    pub token: String,
+    /// Allows unencrypted `http://` urls, ie for a server in the local network
+    #[serde(default)]
+    pub allow_http: bool,
+    /// Entries with a title that contains one of these are dropped
+    #[serde(default)]
+    pub filter: Vec<String>,
+    /// Filter rules for the entries of this calendar
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+    /// Default project for the entries of this calendar
+    #[serde(default)]
+    pub project: String,
+    /// Default tag for the entries of this calendar
+    #[serde(default)]
+    pub tag: String,
+    /// Seconds until the cached calendar is fetched again, [`DEFAULT_CACHE_TTL`] if not set
+    #[serde(default)]
+    pub cache_ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+    /// An ICS file, always downloaded as a whole
+    #[default]
+    Ics,
+    /// A CalDAV server, queried for the events of a time range. The url is a calendar,
+    /// or the server or principal url of an account to discover its calendars.
+    Caldav,
+}
+
+/// Cached calendars are fetched again after one day, unless configured otherwise
+pub const DEFAULT_CACHE_TTL: u64 = 24 * 60 * 60;
+
+impl CalendarSource {
+    pub fn cache_ttl(&self) -> u64 {
+        self.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL)
+    }
+}
+
+/// Keeps or drops ICS entries. An entry is kept if it matches none of the exclude rules and,
+/// if there are include rules, at least one of them.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct FilterRule {
+    #[serde(default)]
+    pub action: FilterAction,
+    #[serde(flatten)]
+    pub condition: EventMatch,
+}
+
+/// Suggests a project and tags for the ICS entries it matches. The first matching rule is applied.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct MappingRule {
+    /// Reported with the entries the rule matched
+    #[serde(default)]
+    pub name: String,
+    #[serde(flatten)]
+    pub condition: EventMatch,
+    /// Replaces the default project of the calendar, if not empty
+    #[serde(default)]
+    pub project: String,
+    /// Added to the default tag of the calendar
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Condition on an ICS entry, shared by filter and mapping rules
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct EventMatch {
+    #[serde(default)]
+    pub field: FilterField,
+    #[serde(default)]
+    pub syntax: PatternSyntax,
+    /// Matches every entry if empty
+    #[serde(default)]
+    pub pattern: String,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// Weekdays the rule applies to, every day if empty
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    /// Time of day (`HH:MM`) of the start of entries the rule applies to, inclusive.
+    /// `time_to` is exclusive, a range that wraps around midnight is allowed.
+    #[serde(default)]
+    pub time_from: Option<String>,
+    #[serde(default)]
+    pub time_to: Option<String>,
+    /// IANA name of the time zone of `weekdays` and times, UTC if not set
+    #[serde(default)]
+    pub time_zone: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    Include,
+    #[default]
+    Exclude,
+}
+
+/// Field of an entry that a rule matches
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterField {
+    #[default]
+    Title,
+    Description,
+    Organizer,
+    Location,
+    /// Matches if one of the categories matches
+    Categories,
+    /// Outlook busy status: `FREE`, `TENTATIVE`, `BUSY`, `OOF` or `WORKINGELSEWHERE`
+    BusyStatus,
+    /// `TENTATIVE` or `CONFIRMED`, empty if not set
+    Status,
+    /// `OPAQUE` or `TRANSPARENT`, empty if not set
+    Transp,
+    /// Join link of the online meeting, empty for meetings in person
+    OnlineMeetingUrl,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternSyntax {
+    /// The field contains the pattern
+    #[default]
+    Contains,
+    /// `*` and `?` wildcards, matching the whole field
+    Glob,
+    /// Regular expression, matching anywhere in the field unless anchored
+    Regex,
+}
+
+/// Name of the source that is derived from `ics_url` and `ics_filter`
+pub const LEGACY_CALENDAR_NAME: &str = "Calendar";
+
+impl Settings {
+    /// All calendars to fetch. A non-empty `ics_url` is included as source named [`LEGACY_CALENDAR_NAME`],
+    /// unless one of the `calendars` has the same url. The `ics_rules` are added to the rules of every source.
+    pub fn calendar_sources(&self) -> Vec<CalendarSource> {
+        let mut sources = self.calendars.clone();
+        if !self.ics_url.is_empty() && !sources.iter().any(|source| source.url == self.ics_url) {
+            sources.insert(0, CalendarSource {
+                name: LEGACY_CALENDAR_NAME.to_string(),
+                url: self.ics_url.clone(),
+                filter: self.ics_filter.clone(),
+                ..Default::default()
+            });
+        }
+        for source in &mut sources {
+            source.rules.splice(0..0, self.ics_rules.iter().cloned());
+        }
+        sources
+    }
+
+    /// Checks that the mapping rules only suggest projects and tags of these settings
+    pub fn check_mappings(&self) -> Result<(), String> {
+        for (index, rule) in self.ics_mappings.iter().enumerate() {
+            if !rule.project.is_empty() && !self.projects.contains(&rule.project) {
+                return Err(format!("Mapping rule {}: Unknown project \"{}\"", index + 1, rule.project));
+            }
+            if let Some(tag) = rule.tags.iter().find(|tag| !self.tags.contains(tag)) {
+                return Err(format!("Mapping rule {}: Unknown tag \"{}\"", index + 1, tag));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes credentials: ICS urls usually contain an access key, calendar passwords and tokens and the Gitlab access token
+    pub fn redact_secrets(&mut self) {
+        self.ics_url.clear();
+        self.gitlab_access_token.clear();
+        for calendar in &mut self.calendars {
+            calendar.url.clear();
+            calendar.password.clear();
+            calendar.token.clear();
+        }
+    }
+
+    /// Fills credentials that have been removed by [`Settings::redact_secrets`] from `other`
+    pub fn restore_secrets(&mut self, other: &Self) {
+        if self.ics_url.is_empty() {
+            self.ics_url.clone_from(&other.ics_url);
+        }
+        if self.gitlab_access_token.is_empty() {
+            self.gitlab_access_token.clone_from(&other.gitlab_access_token);
+        }
+        for calendar in &mut self.calendars {
+            if let Some(other) = other.calendars.iter().find(|other| other.name == calendar.name) {
+                if calendar.url.is_empty() {
+                    calendar.url.clone_from(&other.url);
+                }
+                if calendar.password.is_empty() {
+                    calendar.password.clone_from(&other.password);
+                }
+                if calendar.token.is_empty() {
+                    calendar.token.clone_from(&other.token);
+                }
+            }
+        }
+    }
+
+    /// Adds projects, tags, filters, filter and mapping rules and calendars of `other` and fills empty fields from it.
+    /// Calendars with the same name are merged.
+    pub fn merge(&mut self, other: &Self) {
+        fn union<T: PartialEq + Clone>(list: &mut Vec<T>, other: &[T]) {
+            for value in other {
+                if !list.contains(value) {
+                    list.push(value.clone());
+                }
+            }
+        }
+        fn fill(value: &mut String, other: &str) {
+            if value.is_empty() {
+                *value = other.to_string();
+            }
+        }
+
+        union(&mut self.ics_filter, &other.ics_filter);
+        union(&mut self.projects, &other.projects);
+        union(&mut self.tags, &other.tags);
+        union(&mut self.ics_rules, &other.ics_rules);
+        union(&mut self.ics_mappings, &other.ics_mappings);
+        fill(&mut self.ics_url, &other.ics_url);
+        fill(&mut self.name, &other.name);
+        fill(&mut self.company, &other.company);
+        fill(&mut self.client, &other.client);
+        fill(&mut self.gitlab_url, &other.gitlab_url);
+        fill(&mut self.gitlab_access_token, &other.gitlab_access_token);
+        for other in &other.calendars {
+            match self.calendars.iter_mut().find(|calendar| calendar.name == other.name) {
+                Some(calendar) => {
+                    union(&mut calendar.filter, &other.filter);
+                    union(&mut calendar.rules, &other.rules);
+                    fill(&mut calendar.url, &other.url);
+                    fill(&mut calendar.username, &other.username);
+                    fill(&mut calendar.password, &other.password);
+                    fill(&mut calendar.token, &other.token);
+                    fill(&mut calendar.project, &other.project);
+                    fill(&mut calendar.tag, &other.tag);
+                    fill(&mut calendar.calendar, &other.calendar);
+                    calendar.cache_ttl = calendar.cache_ttl.or(other.cache_ttl);
+                }
+                None => self.calendars.push(other.clone()),
+            }
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, de::IgnoredAny, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use super::{unix_secs, MonthSummary, RetentionPolicy, RevisionInfo, Storage};
+use crate::settings::Settings;
+use crate::timesheet::{MonthKey, OneMonth};
+
+fn with_extension_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// The last good version of a data file is kept as `<file>.bak`
+fn backup_path(path: &Path) -> PathBuf {
+    with_extension_suffix(path, ".bak")
+}
+
+/// Writes a data file crash-safe: The contents go to a temporary file that is synced to disk and
+/// then renamed over the old file. A power cut leaves either the old or the new file behind.
+///
+/// If the old file is valid JSON it is kept as `<file>.bak` for [`read_json`].
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let temp_path = with_extension_suffix(path, &format!(".{}.tmp", TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+
+    let result = async {
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        if let Ok(old) = fs::read(path).await {
+            if serde_json::from_slice::<IgnoredAny>(&old).is_ok() {
+                let backup = backup_path(path);
+                let _ = fs::remove_file(&backup).await;
+                if fs::hard_link(path, &backup).await.is_err() {
+                    fs::write(&backup, &old).await?;
+                }
+            }
+        }
+
+        fs::rename(&temp_path, path).await?;
+
+        // Persist the rename itself. Not supported on all platforms, hence errors are ignored.
+        if let Some(dir) = path.parent() {
+            if let Ok(dir) = File::open(dir).await {
+                let _ = dir.sync_all().await;
+            }
+        }
+        Ok(())
+    }.await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// Serializes `value` as JSON and writes it with [`write_atomic`]
+pub async fn write_json<T: Serialize + Sync>(path: &Path, value: &T) -> Result<(), io::Error> {
+    write_atomic(path, &serde_json::to_vec(value)?).await
+}
+
+/// Reads a data file and parses it with `parse`. If the file cannot be read or parsed, the `.bak`
+/// copy of the last good version is used instead. A missing file is reported as [`io::ErrorKind::NotFound`].
+async fn read_checked<T>(path: &Path, parse: impl Fn(&[u8]) -> serde_json::Result<T>) -> Result<T, io::Error> {
+    let error = match fs::read(path).await {
+        Ok(contents) => match parse(&contents) {
+            Ok(value) => return Ok(value),
+            Err(err) => io::Error::from(err),
+        },
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+        Err(err) => err,
+    };
+
+    let backup = backup_path(path);
+    match fs::read(&backup).await.map(|contents| parse(&contents)) {
+        Ok(Ok(value)) => {
+            tracing::warn!("Failed to read {}: {}. Using backup {}", path.display(), error, backup.display());
+            Ok(value)
+        }
+        _ => Err(error),
+    }
+}
+
+/// Reads and parses a JSON data file, see [`read_checked`]
+pub async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, io::Error> {
+    read_checked(path, |contents| serde_json::from_slice(contents)).await
+}
+
+/// Reads a JSON data file without parsing it, see [`read_checked`]
+pub async fn read_json_bytes(path: &Path) -> Result<Vec<u8>, io::Error> {
+    read_checked(path, |contents| serde_json::from_slice::<IgnoredAny>(contents).map(|_| contents.to_vec())).await
+}
+
+/// Removes a data file together with its backup
+pub async fn remove_file(path: &Path) -> Result<(), io::Error> {
+    fs::remove_file(path).await?;
+    match fs::remove_file(backup_path(path)).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Maps [`io::ErrorKind::NotFound`] to `None`
+fn optional<T>(result: Result<T, io::Error>) -> Result<Option<T>, io::Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// A revision file is either `<revision>.timesheet` for a save or `<revision>.deleted` for a delete
+fn parse_revision_file_name(name: &str) -> Option<(u64, bool)> {
+    let (number, extension) = name.split_once('.')?;
+    let deleted = match extension {
+        "timesheet" => false,
+        "deleted" => true,
+        _ => return None,
+    };
+    Some((number.parse().ok()?, deleted))
+}
+
+fn revision_file_name(revision: u64, deleted: bool) -> String {
+    format!("{}.{}", revision, if deleted { "deleted" } else { "timesheet" })
+}
+
+/// The original storage layout: JSON files in the data directory (`TIME_SHEET_DIR`).
+///
+/// * `settings.json`
+/// * `<date>.timesheet`, ie `2022_12.timesheet`
+/// * `revisions/<date>/<revision>.timesheet` and `revisions/<date>/<revision>.deleted`
+/// * `<name>.json` for cached data, ie `ics.json`
+pub struct FileStorage {
+    dir: PathBuf,
+    retention: RetentionPolicy,
+    /// Revision numbers are picked from the existing files, hence revisions are added one at a time
+    revision_lock: Mutex<()>,
+}
+
+impl FileStorage {
+    pub async fn new(dir: PathBuf, retention: RetentionPolicy) -> Result<Self, io::Error> {
+        fs::create_dir_all(&dir).await?;
+        let storage = Self { dir, retention, revision_lock: Mutex::new(()) };
+        storage.rename_legacy_timesheets().await?;
+        Ok(storage)
+    }
+
+    /// Older versions stored some timesheets with a zero-padded month, ie `2022_04.timesheet`. They are
+    /// renamed to the name of their [`MonthKey`], so that they can be read and deleted like the others.
+    async fn rename_legacy_timesheets(&self) -> Result<(), io::Error> {
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let name = file.file_name();
+            let Some(date) = name.to_str().and_then(|name| name.strip_suffix(".timesheet")) else {
+                continue;
+            };
+            let Ok(key) = date.parse::<MonthKey>() else {
+                continue;
+            };
+            if key.to_string() == date {
+                continue;
+            }
+            let path = self.timesheet_path(key);
+            if fs::metadata(&path).await.is_ok() {
+                tracing::warn!("Not renaming timesheet {}.timesheet, {} exists already", date, path.display());
+            } else {
+                tracing::info!("Renaming timesheet {}.timesheet to {}", date, path.display());
+                fs::rename(file.path(), &path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn settings_path(&self) -> PathBuf {
+        self.dir.join("settings.json")
+    }
+
+    fn timesheet_path(&self, date: MonthKey) -> PathBuf {
+        self.dir.join(format!("{date}.timesheet"))
+    }
+
+    fn cache_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+
+    /// Past versions of a timesheet are kept in `revisions/<date>/<revision>.timesheet`
+    fn revision_dir(&self, date: MonthKey) -> PathBuf {
+        self.dir.join("revisions").join(date.to_string())
+    }
+
+    /// Revision numbers and whether they are deletions, in ascending order
+    async fn revision_files(&self, date: &MonthKey) -> Result<Vec<(u64, bool)>, io::Error> {
+        let mut revisions = Vec::new();
+        let Some(mut dir) = optional(fs::read_dir(self.revision_dir(*date)).await)? else {
+            return Ok(revisions);
+        };
+        while let Some(file) = dir.next_entry().await? {
+            revisions.extend(file.file_name().to_str().and_then(parse_revision_file_name));
+        }
+        revisions.sort_unstable();
+        Ok(revisions)
+    }
+
+    async fn read_revision(&self, date: &MonthKey, revision: u64, deleted: bool) -> Result<OneMonth, io::Error> {
+        read_json(&self.revision_dir(*date).join(revision_file_name(revision, deleted))).await
+    }
+
+    async fn add_revision(&self, date: &MonthKey, month: &OneMonth, deleted: bool) -> Result<(), io::Error> {
+        let _lock = self.revision_lock.lock().await;
+        let dir = self.revision_dir(*date);
+        fs::create_dir_all(&dir).await?;
+        let revision = self.revision_files(date).await?.last().map_or(1, |(last, _)| last + 1);
+        write_json(&dir.join(revision_file_name(revision, deleted)), month).await?;
+        self.prune_revisions(date).await
+    }
+
+    /// Removes revisions according to the [`RetentionPolicy`]
+    async fn prune_revisions(&self, date: &MonthKey) -> Result<(), io::Error> {
+        let revisions = self.revision_files(date).await?;
+        let Some((_, older)) = revisions.split_last() else {
+            return Ok(());
+        };
+        let excess = revisions.len().saturating_sub(self.retention.max_revisions.max(1));
+        let dir = self.revision_dir(*date);
+
+        for (index, (revision, deleted)) in older.iter().enumerate() {
+            let path = dir.join(revision_file_name(*revision, *deleted));
+            let expired = match self.retention.max_age {
+                Some(max_age) => fs::metadata(&path).await?.modified()?.elapsed().unwrap_or_default() > max_age,
+                None => false,
+            };
+            if index < excess || expired {
+                tracing::debug!("Remove revision {} of timesheet {}", revision, date);
+                remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn get_settings(&self) -> Result<Option<Settings>, io::Error> {
+        optional(read_json(&self.settings_path()).await)
+    }
+
+    async fn set_settings(&self, settings: &Settings) -> Result<(), io::Error> {
+        write_json(&self.settings_path(), settings).await
+    }
+
+    async fn get_month(&self, date: &MonthKey) -> Result<Option<OneMonth>, io::Error> {
+        optional(read_json(&self.timesheet_path(*date)).await)
+    }
+
+    async fn put_month(&self, date: &MonthKey, month: &OneMonth) -> Result<(), io::Error> {
+        write_json(&self.timesheet_path(*date), month).await?;
+        self.add_revision(date, month, false).await
+    }
+
+    async fn delete_month(&self, date: &MonthKey) -> Result<bool, io::Error> {
+        let Some(current) = self.get_month(date).await? else {
+            return Ok(false);
+        };
+        self.add_revision(date, &current, true).await?;
+        remove_file(&self.timesheet_path(*date)).await?;
+        Ok(true)
+    }
+
+    /// All `<date>.timesheet` files of the data directory. Files that cannot be parsed are skipped.
+    async fn list_months(&self) -> Result<Vec<MonthSummary>, io::Error> {
+        let mut list = Vec::new();
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let name = file.file_name();
+            let Some(stem) = name.to_str().and_then(|name| name.strip_suffix(".timesheet")) else {
+                continue;
+            };
+            let Ok(date) = stem.parse::<MonthKey>() else {
+                tracing::warn!("Skipping timesheet with invalid name {}.timesheet", stem);
+                continue;
+            };
+            if date.to_string() != stem {
+                // Left behind by `rename_legacy_timesheets` because both names exist
+                tracing::warn!("Skipping timesheet {}.timesheet, {}.timesheet is used instead", stem, date);
+                continue;
+            }
+            match read_json::<OneMonth>(&file.path()).await {
+                Ok(month) => list.push(MonthSummary::new(date, &month, unix_secs(file.metadata().await?.modified()?))),
+                Err(err) => tracing::warn!("Skipping timesheet {}: {}", date, err),
+            }
+        }
+        Ok(list)
+    }
+
+    async fn list_revisions(&self, date: &MonthKey) -> Result<Vec<RevisionInfo>, io::Error> {
+        let mut list = Vec::new();
+        for (revision, deleted) in self.revision_files(date).await? {
+            let path = self.revision_dir(*date).join(revision_file_name(revision, deleted));
+            let saved = unix_secs(fs::metadata(&path).await?.modified()?);
+            let change_id = self.read_revision(date, revision, deleted).await?.change_id;
+            list.push(RevisionInfo { revision, change_id, saved, deleted });
+        }
+        Ok(list)
+    }
+
+    async fn get_revision(&self, date: &MonthKey, revision: u64) -> Result<Option<OneMonth>, io::Error> {
+        let found = self.revision_files(date).await?.into_iter().find(|(number, _)| *number == revision);
+        match found {
+            Some((revision, deleted)) => Ok(Some(self.read_revision(date, revision, deleted).await?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_revision(&self, date: &MonthKey, change_id: u64) -> Result<Option<OneMonth>, io::Error> {
+        for (revision, deleted) in self.revision_files(date).await?.into_iter().rev() {
+            let month = self.read_revision(date, revision, deleted).await?;
+            if month.change_id == change_id {
+                return Ok(Some(month));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_cache(&self, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        optional(read_json_bytes(&self.cache_path(name)).await)
+    }
+
+    async fn set_cache(&self, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        write_atomic(&self.cache_path(name), data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timesheet::{DayEntry, OneDay};
+
+    #[tokio::test]
+    async fn read_falls_back_to_backup() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir().join(format!("timesheet-store-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join("settings.json");
+
+        write_json(&path, &vec![1]).await?;
+        write_json(&path, &vec![2]).await?;
+        assert_eq!(read_json::<Vec<i32>>(&path).await?, vec![2]);
+
+        // A truncated file, as left behind by a power cut during a non-atomic write
+        fs::write(&path, b"[").await?;
+        assert_eq!(read_json::<Vec<i32>>(&path).await?, vec![1]);
+
+        // The broken file must not replace the last good backup
+        write_json(&path, &vec![3]).await?;
+        fs::write(&path, b"").await?;
+        assert_eq!(read_json::<Vec<i32>>(&path).await?, vec![1]);
+
+        remove_file(&path).await?;
+        assert_eq!(read_json::<Vec<i32>>(&path).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).await
+    }
+
+    #[tokio::test]
+    async fn months_and_revisions() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir().join(format!("timesheet-revisions-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        let retention = RetentionPolicy { max_revisions: 2, max_age: None };
+        let storage = FileStorage::new(dir.clone(), retention).await?;
+        let date: MonthKey = "2023_4".parse().unwrap();
+
+        let mut month = OneMonth { days: vec![OneDay::default(); 30], year: 2023, month: 4, created: 1, change_id: 1 };
+        month.days[2].entries.push(DayEntry { duration: 90, project: vec!["Falco".into()], ..DayEntry::default() });
+        month.days[3].entries.push(DayEntry { duration: 30, ..DayEntry::default() });
+        storage.put_month(&date, &month).await?;
+        month.change_id = 2;
+        storage.put_month(&date, &month).await?;
+        month.change_id = 3;
+        storage.put_month(&date, &month).await?;
+
+        assert_eq!(storage.get_month(&date).await?, Some(month.clone()));
+        let summary = storage.list_months().await?;
+        assert_eq!(summary, vec![MonthSummary::new(date, &month, summary[0].modified)]);
+        assert_eq!((summary[0].booked_minutes, summary[0].filled_days), (120, 2));
+
+        let revisions: Vec<u64> = storage.list_revisions(&date).await?.iter().map(|r| r.change_id).collect();
+        assert_eq!(revisions, vec![2, 3]);
+        assert!(storage.find_revision(&date, 2).await?.is_some());
+        assert!(storage.find_revision(&date, 1).await?.is_none());
+
+        // Concurrent saves each get their own revision
+        let (first, second) = (OneMonth { change_id: 4, ..month.clone() }, OneMonth { change_id: 5, ..month.clone() });
+        let (saved_first, saved_second) = tokio::join!(storage.put_month(&date, &first), storage.put_month(&date, &second));
+        saved_first?;
+        saved_second?;
+        let mut revisions: Vec<u64> = storage.list_revisions(&date).await?.iter().map(|r| r.change_id).collect();
+        revisions.sort_unstable();
+        assert_eq!(revisions, vec![4, 5]);
+
+        assert!(storage.delete_month(&date).await?);
+        assert!(!storage.delete_month(&date).await?);
+        assert_eq!(storage.get_month(&date).await?, None);
+        assert!(storage.list_months().await?.is_empty());
+        let revisions = storage.list_revisions(&date).await?;
+        assert_eq!(revisions.len(), 2);
+        assert!(revisions.last().unwrap().deleted);
+
+        fs::remove_dir_all(&dir).await
+    }
+
+    #[tokio::test]
+    async fn renames_zero_padded_timesheets() -> Result<(), io::Error> {
+        let dir = std::env::temp_dir().join(format!("timesheet-legacy-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir_all(&dir).await?;
+        let april = OneMonth { days: vec![OneDay::default(); 30], year: 2022, month: 4, created: 1, change_id: 1 };
+        let may = OneMonth { days: vec![OneDay::default(); 31], month: 5, ..april.clone() };
+        write_json(&dir.join("2022_04.timesheet"), &april).await?;
+        // If both names exist, the current one is kept
+        write_json(&dir.join("2022_5.timesheet"), &may).await?;
+        write_json(&dir.join("2022_05.timesheet"), &OneMonth { change_id: 0, ..may.clone() }).await?;
+
+        let storage = FileStorage::new(dir.clone(), RetentionPolicy::default()).await?;
+        let date: MonthKey = "2022-04".parse().unwrap();
+        assert_eq!(storage.get_month(&date).await?, Some(april));
+        let mut listed: Vec<_> = storage.list_months().await?.into_iter().map(|summary| (summary.date.to_string(), summary.change_id)).collect();
+        listed.sort();
+        assert_eq!(listed, [("2022_4".to_string(), 1), ("2022_5".to_string(), 1)]);
+
+        assert!(storage.delete_month(&date).await?);
+        assert_eq!(storage.list_months().await?.len(), 1);
+
+        fs::remove_dir_all(&dir).await
+    }
+}
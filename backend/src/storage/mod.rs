@@ -0,0 +1,157 @@
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::settings::Settings;
+use crate::timesheet::{MonthKey, OneMonth};
+
+pub mod file;
+pub mod sqlite;
+
+/// How many revisions of a timesheet are kept. The latest revision is never removed.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    /// Maximum number of revisions per month
+    pub max_revisions: usize,
+    /// Revisions older than this are removed. `None` keeps them regardless of their age.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self { max_revisions: 100, max_age: None }
+    }
+}
+
+/// Metadata of a stored revision
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    pub change_id: u64,
+    /// Unix timestamp in seconds
+    pub saved: u64,
+    /// The revision was recorded when the timesheet got deleted
+    pub deleted: bool,
+}
+
+/// Summary of a stored timesheet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MonthSummary {
+    /// The date key, ie `2022_12`
+    pub date: MonthKey,
+    pub year: i32,
+    pub month: u8,
+    pub change_id: u64,
+    /// Unix timestamp in milliseconds
+    pub created: u64,
+    /// Unix timestamp in seconds
+    pub modified: u64,
+    pub booked_minutes: i64,
+    pub filled_days: usize,
+}
+
+impl MonthSummary {
+    pub fn new(date: MonthKey, month: &OneMonth, modified: u64) -> Self {
+        Self {
+            date,
+            year: month.year,
+            month: month.month,
+            change_id: month.change_id,
+            created: month.created,
+            modified,
+            booked_minutes: month.booked_minutes(),
+            filled_days: month.filled_days(),
+        }
+    }
+}
+
+/// Persistence of settings, timesheets with their revisions and the ICS cache.
+///
+/// [`file::FileStorage`] keeps everything as JSON files in the data directory,
+/// [`sqlite::SqliteStorage`] uses an embedded SQLite database.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Returns `None` if no settings have been stored yet
+    async fn get_settings(&self) -> Result<Option<Settings>, io::Error>;
+    async fn set_settings(&self, settings: &Settings) -> Result<(), io::Error>;
+
+    /// Returns `None` if there is no timesheet for the given date
+    async fn get_month(&self, date: &MonthKey) -> Result<Option<OneMonth>, io::Error>;
+    /// Stores a timesheet and keeps a copy as new revision
+    async fn put_month(&self, date: &MonthKey, month: &OneMonth) -> Result<(), io::Error>;
+    /// Removes a timesheet. The removed version is kept as revision.
+    /// Returns `false` if there was no timesheet for the given date.
+    async fn delete_month(&self, date: &MonthKey) -> Result<bool, io::Error>;
+    async fn list_months(&self) -> Result<Vec<MonthSummary>, io::Error>;
+
+    /// All revisions of a timesheet, oldest first
+    async fn list_revisions(&self, date: &MonthKey) -> Result<Vec<RevisionInfo>, io::Error>;
+    /// A single revision of a timesheet. Returns `None` if it does not exist (anymore).
+    async fn get_revision(&self, date: &MonthKey, revision: u64) -> Result<Option<OneMonth>, io::Error>;
+    /// Finds the most recent revision of a timesheet with the given `change_id`
+    async fn find_revision(&self, date: &MonthKey, change_id: u64) -> Result<Option<OneMonth>, io::Error>;
+
+    /// Cached data like the ICS cache and its metadata. Returns `None` if nothing is cached.
+    async fn get_cache(&self, name: &str) -> Result<Option<Vec<u8>>, io::Error>;
+    async fn set_cache(&self, name: &str, data: &[u8]) -> Result<(), io::Error>;
+}
+
+impl dyn Storage {
+    pub async fn get_cache_json<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, io::Error> {
+        match self.get_cache(name).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_cache_json<T: Serialize + Sync>(&self, name: &str, value: &T) -> Result<(), io::Error> {
+        self.set_cache(name, &serde_json::to_vec(value)?).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+    /// JSON files in the data directory
+    File,
+    /// Embedded SQLite database
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "file" => Ok(Self::File),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("Unknown storage \"{value}\". Expected file or sqlite")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub kind: StorageKind,
+    /// Data directory of the file storage
+    pub data_dir: PathBuf,
+    /// Database file of the SQLite storage
+    pub database_path: PathBuf,
+    pub retention: RetentionPolicy,
+}
+
+/// Opens the configured storage backend
+pub async fn open(config: &StorageConfig) -> Result<Arc<dyn Storage>, io::Error> {
+    Ok(match config.kind {
+        StorageKind::File => Arc::new(file::FileStorage::new(config.data_dir.clone(), config.retention.clone()).await?),
+        StorageKind::Sqlite => Arc::new(sqlite::SqliteStorage::open(&config.database_path, config.retention.clone()).await?),
+    })
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,355 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use super::{unix_secs, MonthSummary, RetentionPolicy, RevisionInfo, Storage};
+use crate::settings::Settings;
+use crate::timesheet::{MonthKey, OneMonth};
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+
+CREATE TABLE IF NOT EXISTS settings (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS months (
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    change_id INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (year, month)
+);
+
+CREATE TABLE IF NOT EXISTS days (
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    expected_min_hours INTEGER NOT NULL,
+    sick INTEGER NOT NULL,
+    holiday INTEGER NOT NULL,
+    PRIMARY KEY (year, month, day),
+    FOREIGN KEY (year, month) REFERENCES months (year, month) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS entries (
+    id INTEGER PRIMARY KEY,
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    duration INTEGER NOT NULL,
+    description TEXT NOT NULL,
+    FOREIGN KEY (year, month, day) REFERENCES days (year, month, day) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS entries_by_day ON entries (year, month, day);
+
+CREATE TABLE IF NOT EXISTS entry_projects (
+    entry_id INTEGER NOT NULL REFERENCES entries (id) ON DELETE CASCADE,
+    project TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS entry_projects_by_entry ON entry_projects (entry_id);
+CREATE INDEX IF NOT EXISTS entry_projects_by_project ON entry_projects (project);
+
+CREATE TABLE IF NOT EXISTS entry_tags (
+    entry_id INTEGER NOT NULL REFERENCES entries (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS entry_tags_by_entry ON entry_tags (entry_id);
+CREATE INDEX IF NOT EXISTS entry_tags_by_tag ON entry_tags (tag);
+
+CREATE TABLE IF NOT EXISTS revisions (
+    year INTEGER NOT NULL,
+    month INTEGER NOT NULL,
+    revision INTEGER NOT NULL,
+    change_id INTEGER NOT NULL,
+    saved INTEGER NOT NULL,
+    deleted INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (year, month, revision)
+);
+
+CREATE TABLE IF NOT EXISTS cache (
+    name TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    modified INTEGER NOT NULL
+);
+";
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Parses a JSON column. Parse errors are reported as conversion errors of the given column.
+fn from_json<T: serde::de::DeserializeOwned>(column: usize, data: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(data).map_err(|err| rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(err)))
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
+}
+
+/// Stores everything in an embedded SQLite database (`DATABASE_PATH`).
+///
+/// Each timesheet is kept as JSON document in `months` and additionally broken down into the
+/// `days`, `entries`, `entry_projects` and `entry_tags` tables, so that reports can be answered
+/// with SQL instead of parsing every month.
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+    retention: RetentionPolicy,
+}
+
+impl SqliteStorage {
+    pub async fn open(path: &Path, retention: RetentionPolicy) -> Result<Self, io::Error> {
+        let path = path.to_owned();
+        let connection = tokio::task::spawn_blocking(move || {
+            let connection = Connection::open(path)?;
+            connection.pragma_update(None, "journal_mode", "WAL")?;
+            connection.execute_batch(SCHEMA)?;
+            Ok::<_, rusqlite::Error>(connection)
+        }).await.map_err(to_io_error)?.map_err(to_io_error)?;
+
+        Ok(Self { connection: Arc::new(Mutex::new(connection)), retention })
+    }
+
+    /// Runs `f` on a blocking thread with exclusive access to the database connection
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, io::Error>
+        where T: Send + 'static,
+              F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().map_err(|_| io::Error::other("Database connection poisoned"))?;
+            f(&mut connection).map_err(to_io_error)
+        }).await.map_err(to_io_error)?
+    }
+
+    fn add_revision(tx: &Transaction, date: MonthKey, month: &OneMonth, deleted: bool, retention: &RetentionPolicy) -> rusqlite::Result<()> {
+        let now = unix_secs(SystemTime::now());
+        tx.execute(
+            "INSERT INTO revisions (year, month, revision, change_id, saved, deleted, data)
+             VALUES (?1, ?2, (SELECT COALESCE(MAX(revision), 0) + 1 FROM revisions WHERE year = ?1 AND month = ?2), ?3, ?4, ?5, ?6)",
+            params![date.year, date.month, month.change_id, now, deleted, to_json(month)?],
+        )?;
+
+        // Retention policy. The latest revision is never removed.
+        let expired_before = retention.max_age.map_or(0, |max_age| now.saturating_sub(max_age.as_secs()));
+        tx.execute(
+            "DELETE FROM revisions WHERE year = ?1 AND month = ?2
+               AND revision < (SELECT MAX(revision) FROM revisions WHERE year = ?1 AND month = ?2)
+               AND (saved < ?4 OR revision NOT IN
+                    (SELECT revision FROM revisions WHERE year = ?1 AND month = ?2 ORDER BY revision DESC LIMIT ?3))",
+            params![date.year, date.month, retention.max_revisions.max(1), expired_before],
+        )?;
+        Ok(())
+    }
+
+    fn insert_month(tx: &Transaction, date: MonthKey, month: &OneMonth) -> rusqlite::Result<()> {
+        tx.execute(
+            "INSERT INTO months (year, month, change_id, created, modified, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (year, month) DO UPDATE SET
+               change_id = excluded.change_id, created = excluded.created, modified = excluded.modified, data = excluded.data",
+            params![date.year, date.month, month.change_id, month.created, unix_secs(SystemTime::now()), to_json(month)?],
+        )?;
+        tx.execute("DELETE FROM days WHERE year = ?1 AND month = ?2", params![date.year, date.month])?;
+
+        let mut insert_day = tx.prepare("INSERT INTO days (year, month, day, expected_min_hours, sick, holiday) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        let mut insert_entry = tx.prepare("INSERT INTO entries (year, month, day, position, duration, description) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        let mut insert_project = tx.prepare("INSERT INTO entry_projects (entry_id, project) VALUES (?1, ?2)")?;
+        let mut insert_tag = tx.prepare("INSERT INTO entry_tags (entry_id, tag) VALUES (?1, ?2)")?;
+
+        for (index, day) in month.days.iter().enumerate() {
+            let day_of_month = index + 1;
+            insert_day.execute(params![date.year, date.month, day_of_month, day.expected_min_hours, day.sick, day.holiday])?;
+            for (position, entry) in day.entries.iter().enumerate() {
+                insert_entry.execute(params![date.year, date.month, day_of_month, position, entry.duration, entry.description])?;
+                let entry_id = tx.last_insert_rowid();
+                for project in &entry.project {
+                    insert_project.execute(params![entry_id, project])?;
+                }
+                for tag in &entry.tags {
+                    insert_tag.execute(params![entry_id, tag])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_settings(&self) -> Result<Option<Settings>, io::Error> {
+        self.with_connection(|connection| {
+            let data: Option<String> = connection.query_row("SELECT data FROM settings WHERE id = 1", [], |row| row.get(0)).optional()?;
+            data.map(|data| from_json(0, &data)).transpose()
+        }).await
+    }
+
+    async fn set_settings(&self, settings: &Settings) -> Result<(), io::Error> {
+        let data = to_json(settings).map_err(to_io_error)?;
+        self.with_connection(move |connection| {
+            connection.execute("INSERT INTO settings (id, data) VALUES (1, ?1) ON CONFLICT (id) DO UPDATE SET data = excluded.data", [data])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_month(&self, date: &MonthKey) -> Result<Option<OneMonth>, io::Error> {
+        let date = *date;
+        self.with_connection(move |connection| {
+            let json: Option<String> = connection.query_row(
+                "SELECT data FROM months WHERE year = ?1 AND month = ?2", params![date.year, date.month], |row| row.get(0),
+            ).optional()?;
+            json.map(|json| from_json(0, &json)).transpose()
+        }).await
+    }
+
+    async fn put_month(&self, date: &MonthKey, month: &OneMonth) -> Result<(), io::Error> {
+        let (date, month, retention) = (*date, month.clone(), self.retention.clone());
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            Self::insert_month(&tx, date, &month)?;
+            Self::add_revision(&tx, date, &month, false, &retention)?;
+            tx.commit()
+        }).await
+    }
+
+    async fn delete_month(&self, date: &MonthKey) -> Result<bool, io::Error> {
+        let Some(current) = self.get_month(date).await? else {
+            return Ok(false);
+        };
+        let (date, retention) = (*date, self.retention.clone());
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            Self::add_revision(&tx, date, &current, true, &retention)?;
+            tx.execute("DELETE FROM months WHERE year = ?1 AND month = ?2", params![date.year, date.month])?;
+            tx.commit()?;
+            Ok(true)
+        }).await
+    }
+
+    async fn list_months(&self) -> Result<Vec<MonthSummary>, io::Error> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT m.year, m.month, m.change_id, m.created, m.modified,
+                        (SELECT COALESCE(SUM(e.duration), 0) FROM entries e WHERE e.year = m.year AND e.month = m.month),
+                        (SELECT COUNT(DISTINCT e.day) FROM entries e WHERE e.year = m.year AND e.month = m.month)
+                 FROM months m ORDER BY m.year, m.month",
+            )?;
+            let rows = statement.query_map([], |row| {
+                let (year, month) = (row.get(0)?, row.get(1)?);
+                Ok(MonthSummary {
+                    date: MonthKey { year, month },
+                    year,
+                    month,
+                    change_id: row.get(2)?,
+                    created: row.get(3)?,
+                    modified: row.get(4)?,
+                    booked_minutes: row.get(5)?,
+                    filled_days: row.get(6)?,
+                })
+            })?;
+            rows.collect()
+        }).await
+    }
+
+    async fn list_revisions(&self, date: &MonthKey) -> Result<Vec<RevisionInfo>, io::Error> {
+        let date = *date;
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT revision, change_id, saved, deleted FROM revisions WHERE year = ?1 AND month = ?2 ORDER BY revision",
+            )?;
+            let rows = statement.query_map(params![date.year, date.month], |row| Ok(RevisionInfo {
+                revision: row.get(0)?,
+                change_id: row.get(1)?,
+                saved: row.get(2)?,
+                deleted: row.get(3)?,
+            }))?;
+            rows.collect()
+        }).await
+    }
+
+    async fn get_revision(&self, date: &MonthKey, revision: u64) -> Result<Option<OneMonth>, io::Error> {
+        let date = *date;
+        self.with_connection(move |connection| {
+            let json: Option<String> = connection.query_row(
+                "SELECT data FROM revisions WHERE year = ?1 AND month = ?2 AND revision = ?3",
+                params![date.year, date.month, revision], |row| row.get(0),
+            ).optional()?;
+            json.map(|json| from_json(0, &json)).transpose()
+        }).await
+    }
+
+    async fn find_revision(&self, date: &MonthKey, change_id: u64) -> Result<Option<OneMonth>, io::Error> {
+        let date = *date;
+        self.with_connection(move |connection| {
+            let json: Option<String> = connection.query_row(
+                "SELECT data FROM revisions WHERE year = ?1 AND month = ?2 AND change_id = ?3 ORDER BY revision DESC LIMIT 1",
+                params![date.year, date.month, change_id], |row| row.get(0),
+            ).optional()?;
+            json.map(|json| from_json(0, &json)).transpose()
+        }).await
+    }
+
+    async fn get_cache(&self, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        let name = name.to_string();
+        self.with_connection(move |connection| {
+            connection.query_row("SELECT data FROM cache WHERE name = ?1", [name], |row| row.get(0)).optional()
+        }).await
+    }
+
+    async fn set_cache(&self, name: &str, data: &[u8]) -> Result<(), io::Error> {
+        let (name, data) = (name.to_string(), data.to_vec());
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO cache (name, data, modified) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET data = excluded.data, modified = excluded.modified",
+                params![name, data, unix_secs(SystemTime::now())],
+            )?;
+            Ok(())
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timesheet::{DayEntry, OneDay};
+
+    #[tokio::test]
+    async fn months_and_revisions() -> Result<(), io::Error> {
+        let retention = RetentionPolicy { max_revisions: 2, max_age: None };
+        let storage = SqliteStorage::open(Path::new(":memory:"), retention).await?;
+        let date: MonthKey = "2023_4".parse().unwrap();
+
+        let mut month = OneMonth { days: vec![OneDay::default(); 30], year: 2023, month: 4, created: 1, change_id: 1 };
+        month.days[2].entries.push(DayEntry { duration: 90, project: vec!["Falco".into()], ..DayEntry::default() });
+        month.days[3].entries.push(DayEntry { duration: 30, ..DayEntry::default() });
+        storage.put_month(&date, &month).await?;
+        month.change_id = 2;
+        storage.put_month(&date, &month).await?;
+        month.change_id = 3;
+        storage.put_month(&date, &month).await?;
+
+        assert_eq!(storage.get_month(&date).await?, Some(month.clone()));
+        let summary = storage.list_months().await?;
+        assert_eq!(summary, vec![MonthSummary::new(date, &month, summary[0].modified)]);
+        assert_eq!((summary[0].booked_minutes, summary[0].filled_days), (120, 2));
+
+        let revisions: Vec<u64> = storage.list_revisions(&date).await?.iter().map(|r| r.change_id).collect();
+        assert_eq!(revisions, vec![2, 3]);
+        assert!(storage.find_revision(&date, 2).await?.is_some());
+
+        assert!(storage.delete_month(&date).await?);
+        assert!(!storage.delete_month(&date).await?);
+        assert_eq!(storage.get_month(&date).await?, None);
+        assert!(storage.list_months().await?.is_empty());
+        assert!(storage.list_revisions(&date).await?.last().unwrap().deleted);
+        Ok(())
+    }
+}
@@ -1,20 +1,45 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
-#[derive(Clone, Debug, Default)]
+use base64::Engine;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::storage::Storage;
+use crate::timesheet::MonthKey;
+
+#[derive(Clone)]
 pub struct Store {
     api_token: String,
-    pub upload_dir: PathBuf,
+    pub storage: Arc<dyn Storage>,
+    month_locks: Arc<Mutex<HashMap<MonthKey, Arc<tokio::sync::Mutex<()>>>>>,
 }
 
 impl Store {
-    pub fn new(api_token: String, upload_dir: PathBuf) -> Self {
+    pub fn new(api_token: String, storage: Arc<dyn Storage>) -> Self {
         Self {
             api_token,
-            upload_dir,
+            storage,
+            month_locks: Arc::default(),
         }
     }
 
+    /// Waits until no other request changes the timesheet of `date`. Hold the guard from reading the
+    /// current version until the new one is written, so that checks on the current version stay valid.
+    pub async fn lock_month(&self, date: MonthKey) -> OwnedMutexGuard<()> {
+        let lock = self.month_locks.lock().unwrap().entry(date).or_default().clone();
+        lock.lock_owned().await
+    }
+
     pub fn api_token_check(&self, auth_header: &str) -> bool {
         auth_header == format!("Bearer {}", self.api_token)
     }
+
+    /// HTTP basic auth with the token as password, for clients that can not send a bearer token, like calendar
+    /// apps subscribing to the timesheets feed. The user name is ignored.
+    pub fn basic_auth_check(&self, auth_header: &str) -> bool {
+        auth_header.strip_prefix("Basic ")
+            .and_then(|credentials| base64::engine::general_purpose::STANDARD.decode(credentials).ok())
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .is_some_and(|credentials| credentials.split_once(':').is_some_and(|(_, password)| password == self.api_token))
+    }
 }
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{DayEntry, OneDay, OneMonth};
+
+/// A change that was made to the same field or entry on the server and by the client
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// Day of the month, starting with 1
+    pub day: usize,
+    /// Conflicting field of the day, ie `sick` or `entries[2]`
+    pub field: String,
+    pub base: Value,
+    pub server: Value,
+    pub client: Value,
+}
+
+/// Response body for a merge that could not be completed automatically (409 Conflict)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MergeConflicts {
+    pub error: String,
+    pub conflicts: Vec<Conflict>,
+    /// The current server copy
+    pub server: OneMonth,
+}
+
+/// Three-way merge of a single value. Returns `None` if both sides changed it differently.
+fn merge_value<'a, T: PartialEq>(base: &'a T, server: &'a T, client: &'a T) -> Option<&'a T> {
+    if server == client || client == base {
+        Some(server)
+    } else if server == base {
+        Some(client)
+    } else {
+        None
+    }
+}
+
+fn conflict<T: Serialize>(day: usize, field: String, base: &T, server: &T, client: &T) -> Conflict {
+    let to_value = |v: &T| serde_json::to_value(v).unwrap_or_default();
+    Conflict { day: day + 1, field, base: to_value(base), server: to_value(server), client: to_value(client) }
+}
+
+/// Longest common subsequence of equal entries, as pairs of indices into `a` and `b`
+fn common_entries(a: &[DayEntry], b: &[DayEntry]) -> Vec<(usize, usize)> {
+    // lengths[i][j] is the length of the common subsequence of a[i..] and b[j..]
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] { lengths[i + 1][j + 1] + 1 } else { lengths[i + 1][j].max(lengths[i][j + 1]) };
+        }
+    }
+
+    let (mut i, mut j, mut pairs) = (0, 0, Vec::new());
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+/// Aligns the entries of one side with the base entries. Unchanged entries are found as common
+/// subsequence, the entries between them are paired by position as changed entries.
+///
+/// Returns what became of each base entry (`None` if it was removed), and the added entries by the
+/// number of base entries in front of them.
+fn align<'a>(base: &[DayEntry], side: &'a [DayEntry]) -> (Vec<Option<&'a DayEntry>>, Vec<Vec<&'a DayEntry>>) {
+    let mut edited = Vec::with_capacity(base.len());
+    let mut added = vec![Vec::new(); base.len() + 1];
+
+    let (mut b, mut s) = (0, 0);
+    for (next_b, next_s) in common_entries(base, side).into_iter().chain([(base.len(), side.len())]) {
+        let between = &side[s..next_s];
+        edited.extend((b..next_b).map(|index| between.get(index - b)));
+        added[next_b].extend(between.iter().skip(next_b - b));
+        if next_b < base.len() {
+            edited.push(Some(&side[next_s]));
+        }
+        (b, s) = (next_b + 1, next_s + 1);
+    }
+
+    (edited, added)
+}
+
+/// Entries are aligned with the base entries on both sides, so removing an entry does not shift the
+/// ones behind it. Entries added at the same place are kept from both sides, server entries first.
+fn merge_entries(day: usize, base: &[DayEntry], server: &[DayEntry], client: &[DayEntry], conflicts: &mut Vec<Conflict>) -> Vec<DayEntry> {
+    let (server_edited, server_added) = align(base, server);
+    let (client_edited, client_added) = align(base, client);
+    let mut merged = Vec::new();
+
+    for index in 0..=base.len() {
+        merged.extend(server_added[index].iter().copied().cloned());
+        merged.extend(client_added[index].iter().filter(|entry| !server_added[index].contains(entry)).copied().cloned());
+
+        let Some(base_entry) = base.get(index) else {
+            break;
+        };
+        let base_entry = Some(base_entry);
+        let (server_entry, client_entry) = (server_edited[index], client_edited[index]);
+        match merge_value(&base_entry, &server_entry, &client_entry) {
+            Some(entry) => merged.extend(entry.cloned()),
+            None => conflicts.push(conflict(day, format!("entries[{index}]"), &base_entry, &server_entry, &client_entry)),
+        }
+    }
+
+    merged
+}
+
+fn merge_day(day: usize, base: &OneDay, server: &OneDay, client: &OneDay, conflicts: &mut Vec<Conflict>) -> OneDay {
+    if let Some(merged) = merge_value(base, server, client) {
+        return merged.clone();
+    }
+
+    let mut merged = server.clone();
+
+    macro_rules! merge_field {
+        ($field:ident) => {
+            match merge_value(&base.$field, &server.$field, &client.$field) {
+                Some(value) => merged.$field = value.clone(),
+                None => conflicts.push(conflict(day, stringify!($field).to_string(), &base.$field, &server.$field, &client.$field)),
+            }
+        };
+    }
+    merge_field!(expected_min_hours);
+    merge_field!(sick);
+    merge_field!(holiday);
+
+    merged.entries = merge_entries(day, &base.entries, &server.entries, &client.entries, conflicts);
+    merged
+}
+
+/// Per-day, per-entry three-way merge of a client upload with the current server copy.
+/// `base` is the revision the client started editing from.
+///
+/// The merged month gets a `change_id` newer than both sides. If the same field or entry
+/// was changed on both sides, the list of conflicts is returned instead.
+pub fn three_way(base: &OneMonth, server: &OneMonth, client: &OneMonth) -> Result<OneMonth, Vec<Conflict>> {
+    let mut conflicts = Vec::new();
+
+    if base.days.len() != server.days.len() || base.days.len() != client.days.len() {
+        return Err(vec![conflict(0, "days".into(), &base.days.len(), &server.days.len(), &client.days.len())]);
+    }
+
+    let days = base.days.iter().zip(&server.days).zip(&client.days).enumerate()
+        .map(|(day, ((base, server), client))| merge_day(day, base, server, client, &mut conflicts))
+        .collect();
+
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    Ok(OneMonth {
+        days,
+        change_id: server.change_id.max(client.change_id) + 1,
+        ..server.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(description: &str, duration: i64) -> DayEntry {
+        DayEntry { description: description.to_string(), duration, ..DayEntry::default() }
+    }
+
+    fn month(change_id: u64) -> OneMonth {
+        let mut days = vec![OneDay::default(); 30];
+        days[0].entries = vec![entry("standup", 15), entry("review", 60)];
+        OneMonth { days, year: 2023, month: 4, created: 1, change_id }
+    }
+
+    #[test]
+    fn non_overlapping_edits() {
+        let base = month(1);
+        let mut server = month(2);
+        server.days[0].entries[0].duration = 30;
+        server.days[4].sick = true;
+        let mut client = month(2);
+        client.days[0].entries[1].description = "code review".into();
+        client.days[0].entries.push(entry("planning", 45));
+        client.days[6].entries.push(entry("support", 120));
+
+        let merged = three_way(&base, &server, &client).unwrap();
+        assert_eq!(merged.change_id, 3);
+        assert_eq!(merged.days[0].entries, vec![entry("standup", 30), entry("code review", 60), entry("planning", 45)]);
+        assert!(merged.days[4].sick);
+        assert_eq!(merged.days[6].entries, vec![entry("support", 120)]);
+    }
+
+    #[test]
+    fn same_entry_conflict() {
+        let base = month(1);
+        let mut server = month(2);
+        server.days[0].entries[1].duration = 90;
+        server.days[1].holiday = true;
+        let mut client = month(2);
+        client.days[0].entries.remove(1);
+        client.days[1].holiday = true;
+
+        let conflicts = three_way(&base, &server, &client).unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].day, 1);
+        assert_eq!(conflicts[0].field, "entries[1]");
+        assert_eq!(conflicts[0].client, Value::Null);
+    }
+
+    #[test]
+    fn removal_next_to_edit() {
+        let mut base = month(1);
+        base.days[0].entries.extend([entry("planning", 45), entry("support", 30)]);
+        let mut server = OneMonth { change_id: 2, ..base.clone() };
+        server.days[0].entries[2].duration = 60;
+        server.days[0].entries.push(entry("retro", 60));
+        let mut client = OneMonth { change_id: 2, ..base.clone() };
+        client.days[0].entries.remove(1);
+        client.days[0].entries[2].description = "support ticket".into();
+        client.days[0].entries.insert(0, entry("mails", 10));
+
+        let merged = three_way(&base, &server, &client).unwrap();
+        assert_eq!(merged.days[0].entries, vec![
+            entry("mails", 10),
+            entry("standup", 15),
+            entry("planning", 60),
+            entry("support ticket", 30),
+            entry("retro", 60),
+        ]);
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+
+pub mod merge;
+mod month_key;
+
+pub use month_key::MonthKey;
+
+/// Maximum bookable minutes for a single day
+const MAX_MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// One activity of a day. Mirrors `DayEntry` in the frontend (`src/assets/js/data.ts`).
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct DayEntry {
+    #[serde(default)]
+    pub import_tags: Vec<String>,
+    /// Duration in minutes
+    pub duration: i64,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub project: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+    /// Unix timestamp in seconds of the start of the calendar event the entry was imported from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct OneDay {
+    #[serde(default)]
+    pub entries: Vec<DayEntry>,
+    /// Show a warning when fewer hours are entered
+    #[serde(default)]
+    pub expected_min_hours: i64,
+    #[serde(default)]
+    pub sick: bool,
+    #[serde(default)]
+    pub holiday: bool,
+}
+
+impl OneDay {
+    /// Sum of all entry durations in minutes
+    pub fn booked_minutes(&self) -> i64 {
+        self.entries.iter().map(|entry| entry.duration).sum()
+    }
+}
+
+/// A monthly timesheet as stored in `<date>.timesheet`, see [`MonthKey`].
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct OneMonth {
+    pub days: Vec<OneDay>,
+    pub year: i32,
+    /// Month of the year, starting with 1
+    pub month: u8,
+    /// Unix timestamp in milliseconds
+    pub created: u64,
+    pub change_id: u64,
+}
+
+/// A single problem found while validating an uploaded timesheet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    /// Path to the offending field, ie `days[3].entries[0].duration`
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { field: field.into(), message: message.into() }
+    }
+}
+
+/// Response body for rejected uploads (422 Unprocessable Entity)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ValidationErrors {
+    pub error: String,
+    pub fields: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new(fields: Vec<FieldError>) -> Self {
+        Self { error: "Invalid timesheet".into(), fields }
+    }
+
+    /// The body could not be parsed into a [`OneMonth`] at all
+    pub fn parse_error(err: &serde_json::Error) -> Self {
+        Self::new(vec![FieldError::new("", err.to_string())])
+    }
+}
+
+/// Number of days of the given month, or `None` for an invalid month
+pub fn days_in_month(year: i32, month: u8) -> Option<u8> {
+    Some(time::Month::try_from(month).ok()?.length(year))
+}
+
+impl OneMonth {
+    /// Sum of all entry durations of the month in minutes
+    pub fn booked_minutes(&self) -> i64 {
+        self.days.iter().map(OneDay::booked_minutes).sum()
+    }
+
+    /// Number of days with at least one entry
+    pub fn filled_days(&self) -> usize {
+        self.days.iter().filter(|day| !day.entries.is_empty()).count()
+    }
+
+    /// Entity tag used for `ETag`/`If-Match` headers
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.change_id)
+    }
+
+    /// Checks the timesheet for structural problems. Projects and tags are compared against the
+    /// given lists from the settings; pass `None` to skip that check.
+    pub fn validate(&self, projects: Option<&[String]>, tags: Option<&[String]>) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        match days_in_month(self.year, self.month) {
+            None => errors.push(FieldError::new("month", format!("{} is not a valid month", self.month))),
+            Some(days) if self.days.len() != days as usize => {
+                errors.push(FieldError::new("days", format!("Expected {} days for {}-{:02}, got {}", days, self.year, self.month, self.days.len())));
+            }
+            Some(_) => {}
+        }
+
+        for (day_index, day) in self.days.iter().enumerate() {
+            if !(0..=24).contains(&day.expected_min_hours) {
+                errors.push(FieldError::new(format!("days[{day_index}].expected_min_hours"), "Must be between 0 and 24"));
+            }
+
+            for (entry_index, entry) in day.entries.iter().enumerate() {
+                let field = format!("days[{day_index}].entries[{entry_index}]");
+                if entry.duration < 0 {
+                    errors.push(FieldError::new(format!("{field}.duration"), "Duration must not be negative"));
+                }
+                if let Some(projects) = projects {
+                    for project in entry.project.iter().filter(|p| !projects.contains(p)) {
+                        errors.push(FieldError::new(format!("{field}.project"), format!("Unknown project \"{project}\"")));
+                    }
+                }
+                if let Some(tags) = tags {
+                    for tag in entry.tags.iter().filter(|t| !tags.contains(t)) {
+                        errors.push(FieldError::new(format!("{field}.tags"), format!("Unknown tag \"{tag}\"")));
+                    }
+                }
+            }
+
+            let minutes = day.booked_minutes();
+            if minutes > MAX_MINUTES_PER_DAY {
+                errors.push(FieldError::new(format!("days[{day_index}].entries"), format!("{minutes} minutes booked, more than 24 hours")));
+            }
+        }
+
+        errors
+    }
+}
+
+/// A day that differs between two versions of a timesheet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DayDiff {
+    /// Day of the month, starting with 1
+    pub day: usize,
+    pub from: Option<OneDay>,
+    pub to: Option<OneDay>,
+}
+
+/// Per-day differences between two versions of a timesheet
+pub fn diff_days(from: &OneMonth, to: &OneMonth) -> Vec<DayDiff> {
+    (0..from.days.len().max(to.days.len()))
+        .filter_map(|index| {
+            let (from, to) = (from.days.get(index), to.days.get(index));
+            (from != to).then(|| DayDiff { day: index + 1, from: from.cloned(), to: to.cloned() })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn month(year: i32, month: u8) -> OneMonth {
+        let days = days_in_month(year, month).unwrap();
+        OneMonth {
+            days: vec![OneDay::default(); days as usize],
+            year,
+            month,
+            created: 1,
+            change_id: 1,
+        }
+    }
+
+    fn entry(duration: i64, project: &str) -> DayEntry {
+        DayEntry { duration, project: vec![project.to_string()], ..DayEntry::default() }
+    }
+
+    #[test]
+    fn valid_month() {
+        let mut sheet = month(2024, 2);
+        assert_eq!(sheet.days.len(), 29);
+        sheet.days[0].entries.push(entry(90, "Falco"));
+        assert!(sheet.validate(Some(&["Falco".to_string()]), Some(&[])).is_empty());
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let mut sheet = month(2023, 4);
+        sheet.days.pop();
+        sheet.days[1].entries.push(entry(-5, "Falco"));
+        sheet.days[2].entries.push(entry(20 * 60, "Falco"));
+        sheet.days[2].entries.push(entry(5 * 60, "Falco"));
+        let errors = sheet.validate(Some(&[]), None);
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec![
+            "days",
+            "days[1].entries[0].duration",
+            "days[1].entries[0].project",
+            "days[2].entries[0].project",
+            "days[2].entries[1].project",
+            "days[2].entries",
+        ]);
+    }
+
+    #[test]
+    fn diff() {
+        let from = month(2023, 4);
+        let mut to = from.clone();
+        to.days[3].sick = true;
+        to.days.pop();
+        let diff = diff_days(&from, &to);
+        assert_eq!(diff.len(), 2);
+        assert_eq!((diff[0].day, diff[1].day), (4, 30));
+        assert!(diff[0].to.as_ref().unwrap().sick);
+        assert!(diff[1].to.is_none());
+    }
+}
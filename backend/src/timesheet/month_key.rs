@@ -0,0 +1,105 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Identifies a monthly timesheet. Parsed from `YYYY_M` (as used by the frontend, ie `2022_4`)
+/// or `YYYY-MM` (ie `2022-04`). Displayed as `YYYY_M`, which is also the file name of a timesheet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MonthKey {
+    pub year: i32,
+    /// Month of the year, starting with 1
+    pub month: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidMonthKey(String);
+
+impl fmt::Display for InvalidMonthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid timesheet date \"{}\". Expected YYYY_M or YYYY-MM, ie 2022_12 or 2022-12", self.0)
+    }
+}
+
+impl std::error::Error for InvalidMonthKey {}
+
+impl MonthKey {
+    /// ISO 8601 form, ie `2022-04`
+    pub fn iso(self) -> String {
+        format!("{:04}-{:02}", self.year, self.month)
+    }
+}
+
+fn all_digits(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|c| c.is_ascii_digit())
+}
+
+impl FromStr for MonthKey {
+    type Err = InvalidMonthKey;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidMonthKey(value.chars().take(32).collect());
+
+        let (year, month) = if let Some((year, month)) = value.split_once('_') {
+            if !(1..=2).contains(&month.len()) {
+                return Err(invalid());
+            }
+            (year, month)
+        } else if let Some((year, month)) = value.split_once('-') {
+            if month.len() != 2 {
+                return Err(invalid());
+            }
+            (year, month)
+        } else {
+            return Err(invalid());
+        };
+
+        if year.len() != 4 || !all_digits(year) || !all_digits(month) {
+            return Err(invalid());
+        }
+
+        let year = year.parse().map_err(|_| invalid())?;
+        let month = month.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month })
+    }
+}
+
+impl fmt::Display for MonthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.year, self.month)
+    }
+}
+
+impl Serialize for MonthKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MonthKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("2022_4".parse(), Ok(MonthKey { year: 2022, month: 4 }));
+        assert_eq!("2022_12".parse(), Ok(MonthKey { year: 2022, month: 12 }));
+        assert_eq!("2022-04".parse(), Ok(MonthKey { year: 2022, month: 4 }));
+        assert_eq!("2022-04".parse::<MonthKey>().unwrap().to_string(), "2022_4");
+        assert_eq!("2022_4".parse::<MonthKey>().unwrap().iso(), "2022-04");
+
+        for invalid in ["", "2022", "2022_13", "2022_0", "2022-4", "22_4", "2022_+4", "../settings", "2022_4/..", "settings.json", "2022_4.timesheet"] {
+            assert!(invalid.parse::<MonthKey>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -9,7 +9,9 @@ interface DayEntry {
     duration: number,
     tags: string[],
     project: string[],
-    description: string
+    description: string,
+    // Unix timestamp in seconds of the imported calendar event
+    start?: number
 }
 
 interface OneDay {
@@ -109,6 +111,10 @@ export async function storeSheet(sheet: OneMonth): Promise<OneMonth> {
         sheet.change_id = 1;
     else
         sheet.change_id += 1;
+    // The backend only accepts whole minutes, earlier calendar imports stored fractions
+    for (const day of sheet.days)
+        for (const entry of day.entries)
+            entry.duration = Math.round(entry.duration);
     await db.months.put(sheet);
 
     console.log("store", sheet);
@@ -135,13 +141,35 @@ interface ICSEntry {
     confirmed: boolean,
     /// Out of office
     oof: boolean,
+    /// Covers the whole day, without duration
+    all_day: boolean,
+    /// Name of the calendar source
+    source: string,
+    /// Suggested by the mapping rules or the defaults of the calendar source, empty if none
+    project: string,
+    tags: string[],
+    /// Mapping rule that suggested project and tags
+    matched_rule?: { index: number, name: string },
+    location: string,
+    organizer: string,
+    categories: string[],
+    busy_status: string,
+    attendees: number,
+    /// TENTATIVE or CONFIRMED, cancelled events are not returned
+    status: string,
+    /// OPAQUE or TRANSPARENT
+    transp: string,
+    online_meeting_url: string,
 }
 
-export async function fetchICS(monthIndex: number, day: number): Promise<ICSEntry[]> {
+export async function fetchICS(date: Date): Promise<ICSEntry[]> {
     const s = get(cloudSettings);
     if (s.cloud_api_key) {
         try {
-            const response = await fetchWithTimeout(s.cloud_url + `/fetch_ics/${monthIndex + 1}/${day}`, {timeout: 12000});
+            const day = `${date.getFullYear()}-${String(date.getMonth() + 1).padStart(2, "0")}-${String(date.getDate()).padStart(2, "0")}`;
+            // The backend cuts the day in our time zone
+            const tz = encodeURIComponent(Intl.DateTimeFormat().resolvedOptions().timeZone);
+            const response = await fetchWithTimeout(s.cloud_url + `/fetch_ics/${date.getMonth() + 1}/${date.getDate()}?from=${day}&to=${day}&tz=${tz}`, {timeout: 12000});
             return await response.json();
         } catch (e) {
             console.log("Failed to fetch ICS", e);
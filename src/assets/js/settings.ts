@@ -36,6 +36,49 @@ interface CloudSettings {
     cloud_api_key: string,
 }
 
+export interface EventMatch {
+    field?: "title" | "description" | "organizer" | "location" | "categories" | "busy_status" | "status" | "transp" | "online_meeting_url",
+    syntax?: "contains" | "glob" | "regex",
+    pattern: string,
+    case_insensitive?: boolean,
+    /// "Mon" to "Sun"
+    weekdays?: string[],
+    /// HH:MM
+    time_from?: string,
+    time_to?: string,
+    time_zone?: string,
+}
+
+export interface FilterRule extends EventMatch {
+    action?: "include" | "exclude",
+}
+
+export interface MappingRule extends EventMatch {
+    name?: string,
+    project?: string,
+    tags?: string[],
+}
+
+export interface CalendarSource {
+    name: string,
+    url: string,
+    kind?: "ics" | "caldav",
+    /// CalDAV: display name of the calendar, all calendars of the account if empty
+    calendar?: string,
+    username: string,
+    password: string,
+    /// Bearer token, used instead of username and password
+    token?: string,
+    /// Allows plain http urls
+    allow_http?: boolean,
+    filter: string[],
+    rules?: FilterRule[],
+    project: string,
+    tag: string,
+    /// Seconds until the calendar is fetched again, one day if not set
+    cache_ttl?: number,
+}
+
 interface Settings {
     ics_url: string,
     ics_filter: string[],
@@ -46,7 +89,20 @@ interface Settings {
     client: string,
     last_updated?: number,
     gitlab_url: string,
-    gitlab_access_token: string
+    gitlab_access_token: string,
+    calendars: CalendarSource[],
+    /// Days before and after now of ICS entries, if not requested otherwise
+    ics_window?: { past_days: number, future_days: number },
+    /// Filter rules for all calendars
+    ics_rules?: FilterRule[],
+    /// Suggest projects and tags for ICS entries, the first matching rule applies
+    ics_mappings?: MappingRule[],
+    /// Times of the events of /api/timesheets.ics
+    timesheet_feed?: {
+        day_start?: string,
+        time_zone?: string,
+        times?: "sequential" | "imported",
+    },
 }
 
 export const localSettings = persistentStore<Settings>("settings", {
@@ -59,6 +115,7 @@ export const localSettings = persistentStore<Settings>("settings", {
     tags: ["meeting"],
     gitlab_url: "",
     gitlab_access_token: "",
+    calendars: [],
     last_updated: 0
 });
 